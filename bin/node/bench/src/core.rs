@@ -41,12 +41,26 @@ impl Path {
 	}
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum BenchmarkCategory {
+	BlockConstruction,
+	BlockImport,
+	StorageRead,
+	StorageWrite,
+	Network,
+	Other,
+}
+
 pub trait BenchmarkDescription {
 	fn path(&self) -> Path;
 
 	fn setup(self: Box<Self>) -> Box<dyn Benchmark>;
 
 	fn name(&self) -> Cow<'static, str>;
+
+	fn category(&self) -> BenchmarkCategory {
+		BenchmarkCategory::Other
+	}
 }
 
 pub trait Benchmark {
@@ -56,6 +70,7 @@ pub trait Benchmark {
 #[derive(Debug, Clone, Serialize)]
 pub struct BenchmarkOutput {
 	name: String,
+	category: BenchmarkCategory,
 	raw_average: u64,
 	average: u64,
 }
@@ -103,6 +118,12 @@ impl std::str::FromStr for Mode {
     }
 }
 
+impl BenchmarkOutput {
+	pub fn category(&self) -> BenchmarkCategory {
+		self.category
+	}
+}
+
 impl fmt::Display for BenchmarkOutput {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -120,6 +141,7 @@ pub fn run_benchmark(
 	mode: Mode,
 ) -> BenchmarkOutput {
 	let name = benchmark.name().to_owned();
+	let category = benchmark.category();
 	let mut benchmark = benchmark.setup();
 
 	let mut durations: Vec<u128> = vec![];
@@ -135,11 +157,70 @@ pub fn run_benchmark(
 
 	BenchmarkOutput {
 		name: name.into(),
+		category,
 		raw_average,
 		average,
 	}
 }
 
+pub struct BenchmarkSuite {
+	descriptions: Vec<Box<dyn BenchmarkDescription>>,
+	outputs: Vec<BenchmarkOutput>,
+}
+
+impl BenchmarkSuite {
+	pub fn new(descriptions: Vec<Box<dyn BenchmarkDescription>>) -> Self {
+		BenchmarkSuite { descriptions, outputs: Vec::new() }
+	}
+
+	/// Standard suite: import of blocks of transfers, with every execution profile and
+	/// database type.
+	pub fn standard_suite() -> Self {
+		use node_testing::bench::{Profile, KeyTypes, BlockType, DatabaseType};
+		use crate::import::{ImportBenchmarkDescription, SizeType};
+
+		let mut descriptions = Vec::<Box<dyn BenchmarkDescription>>::new();
+		for profile in [Profile::Wasm, Profile::Native].iter() {
+			for size in [SizeType::Small, SizeType::Medium, SizeType::Full].iter() {
+				for database_type in [DatabaseType::RocksDb, DatabaseType::ParityDb].iter() {
+					descriptions.push(Box::new(ImportBenchmarkDescription {
+						profile: *profile,
+						key_types: KeyTypes::Sr25519,
+						block_type: BlockType::RandomTransfersKeepAlive,
+						size: *size,
+						database_type: *database_type,
+					}));
+				}
+			}
+		}
+
+		Self::new(descriptions)
+	}
+
+	/// Run all benchmarks of the suite in sequence and collect their outputs, in the order
+	/// the benchmarks were added.
+	pub fn run_suite(&mut self, mode: Mode) -> &[BenchmarkOutput] {
+		for benchmark in self.descriptions.drain(..) {
+			log::info!("Starting {}", benchmark.name());
+			let result = run_benchmark(benchmark, mode);
+			log::info!("{}", result);
+
+			self.outputs.push(result);
+		}
+
+		&self.outputs
+	}
+
+	/// Group the collected outputs by category, keeping the run order within a category.
+	pub fn sort_by_category(&mut self) {
+		self.outputs.sort_by_key(|output| output.category());
+	}
+
+	pub fn to_json(&self) -> String {
+		serde_json::to_string(&self.outputs).expect("Failed to construct json")
+	}
+}
+
 macro_rules! matrix(
 	( $var:tt in $over:expr => $tt:expr,  $( $rest:tt )* ) => {
 		{
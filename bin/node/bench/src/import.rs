@@ -140,6 +140,10 @@ impl core::BenchmarkDescription for ImportBenchmarkDescription {
 			self.database_type,
 		).into()
 	}
+
+	fn category(&self) -> core::BenchmarkCategory {
+		core::BenchmarkCategory::BlockImport
+	}
 }
 
 impl core::Benchmark for ImportBenchmark {
@@ -192,6 +196,7 @@ impl core::Benchmark for ImportBenchmark {
 				}
 			}
 		);
+		context.assert_storage_consistent(None);
 
 		if mode == Mode::Profile {
 			std::thread::park_timeout(std::time::Duration::from_secs(1));
@@ -214,3 +219,91 @@ impl core::Benchmark for ImportBenchmark {
 		elapsed
 	}
 }
+
+pub struct ReplayBenchmarkDescription {
+	pub key_types: KeyTypes,
+	pub block_type: BlockType,
+	pub size: SizeType,
+	pub database_type: DatabaseType,
+}
+
+pub struct ReplayBenchmark {
+	database: BenchDb,
+	block: Block,
+}
+
+impl core::BenchmarkDescription for ReplayBenchmarkDescription {
+	fn path(&self) -> Path {
+		let mut path = Path::new(&["node", "replay"]);
+
+		match self.key_types {
+			KeyTypes::Sr25519 => path.push("sr25519"),
+			KeyTypes::Ed25519 => path.push("ed25519"),
+		}
+
+		match self.block_type {
+			BlockType::RandomTransfersKeepAlive => path.push("transfer_keep_alive"),
+			BlockType::RandomTransfersReaping => path.push("transfer_reaping"),
+			BlockType::Noop => path.push("noop"),
+		}
+
+		match self.database_type {
+			DatabaseType::RocksDb => path.push("rocksdb"),
+			DatabaseType::ParityDb => path.push("paritydb"),
+		}
+
+		path.push(&format!("{}", self.size));
+
+		path
+	}
+
+	fn setup(self: Box<Self>) -> Box<dyn core::Benchmark> {
+		let mut bench_db = BenchDb::with_key_types(
+			self.database_type,
+			50_000,
+			self.key_types
+		);
+		let block = bench_db.generate_block(self.block_type.to_content(self.size.transactions()));
+		Box::new(ReplayBenchmark {
+			database: bench_db,
+			block,
+		})
+	}
+
+	fn name(&self) -> Cow<'static, str> {
+		format!(
+			"Replay benchmark ({:?}, {:?} backend)",
+			self.block_type,
+			self.database_type,
+		).into()
+	}
+
+	fn category(&self) -> core::BenchmarkCategory {
+		core::BenchmarkCategory::BlockImport
+	}
+}
+
+impl core::Benchmark for ReplayBenchmark {
+	fn run(&mut self, mode: Mode) -> std::time::Duration {
+		let mut database = self.database.clone();
+
+		if mode == Mode::Profile {
+			std::thread::park_timeout(std::time::Duration::from_secs(3));
+		}
+
+		let elapsed = database.replay_block(&self.block).expect("Failed to replay block");
+
+		if mode == Mode::Profile {
+			std::thread::park_timeout(std::time::Duration::from_secs(1));
+		}
+
+		log::info!(
+			target: "bench-logistics",
+			"replayed block with {} tx, took: {:#?}",
+			self.block.extrinsics.len(),
+			elapsed,
+		);
+
+		elapsed
+	}
+}
@@ -24,9 +24,9 @@ mod generator;
 mod tempdb;
 mod state_sizes;
 
-use crate::core::{run_benchmark, Mode as BenchmarkMode};
+use crate::core::{BenchmarkSuite, Mode as BenchmarkMode};
 use crate::tempdb::DatabaseType;
-use import::{ImportBenchmarkDescription, SizeType};
+use import::{ImportBenchmarkDescription, ReplayBenchmarkDescription, SizeType};
 use trie::{TrieReadBenchmarkDescription, TrieWriteBenchmarkDescription, DatabaseSize};
 use node_testing::bench::{Profile, KeyTypes, BlockType, DatabaseType as BenchDataBaseType};
 use structopt::StructOpt;
@@ -46,6 +46,14 @@ struct Opt {
 	#[structopt(short, long)]
 	json: bool,
 
+	/// Group the results by benchmark category instead of the order they were run in.
+	#[structopt(long)]
+	by_category: bool,
+
+	/// Run the standard suite instead of the benchmarks matching `filter`.
+	#[structopt(long)]
+	standard: bool,
+
 	/// Filter benchmarks.
 	///
 	/// Run with `--list` for the hint of what to filter.
@@ -95,6 +103,16 @@ fn main() {
 		}
 	}
 
+	let mut replay_benchmarks = Vec::new();
+
+	for size in [SizeType::Empty, SizeType::Small, SizeType::Medium].iter() {
+		for block_type in [BlockType::RandomTransfersKeepAlive, BlockType::Noop].iter() {
+			for database_type in [BenchDataBaseType::RocksDb, BenchDataBaseType::ParityDb].iter() {
+				replay_benchmarks.push((size.clone(), block_type.clone(), database_type));
+			}
+		}
+	}
+
 	let benchmarks = matrix!(
 		(profile, size, block_type, database_type) in import_benchmarks.into_iter() =>
 			ImportBenchmarkDescription {
@@ -104,6 +122,13 @@ fn main() {
 				block_type: block_type,
 				database_type: *database_type,
 			},
+		(size, block_type, database_type) in replay_benchmarks.into_iter() =>
+			ReplayBenchmarkDescription {
+				key_types: KeyTypes::Sr25519,
+				size: size,
+				block_type: block_type,
+				database_type: *database_type,
+			},
 		(size, db_type) in
 			[
 				DatabaseSize::Empty, DatabaseSize::Smallest, DatabaseSize::Small,
@@ -141,24 +166,27 @@ fn main() {
 		return;
 	}
 
-	let mut results = Vec::new();
-	for benchmark in benchmarks {
-		if opt.filter.as_ref().map(|f| benchmark.path().has(f)).unwrap_or(true) {
-			log::info!("Starting {}", benchmark.name());
-			let result = run_benchmark(benchmark, opt.mode);
-			log::info!("{}", result);
-
-			results.push(result);
-		}
-	}
-
-	if results.is_empty() {
+	let filter = opt.filter.as_ref();
+	let mut suite = if opt.standard {
+		BenchmarkSuite::standard_suite()
+	} else {
+		BenchmarkSuite::new(
+			benchmarks.into_iter()
+				.filter(|benchmark| filter.map(|f| benchmark.path().has(f)).unwrap_or(true))
+				.collect()
+		)
+	};
+
+	if suite.run_suite(opt.mode).is_empty() {
 		eprintln!("No benchmark was found for query");
 		std::process::exit(1);
 	}
 
+	if opt.by_category {
+		suite.sort_by_category();
+	}
+
 	if opt.json {
-		let json_result: String = serde_json::to_string(&results).expect("Failed to construct json");
-		println!("{}", json_result);
+		println!("{}", suite.to_json());
 	}
 }
@@ -164,6 +164,10 @@ impl core::BenchmarkDescription for TrieReadBenchmarkDescription {
 			self.database_type,
 		).into()
 	}
+
+	fn category(&self) -> core::BenchmarkCategory {
+		core::BenchmarkCategory::StorageRead
+	}
 }
 
 struct Storage(Arc<dyn KeyValueDB>);
@@ -273,6 +277,10 @@ impl core::BenchmarkDescription for TrieWriteBenchmarkDescription {
 			self.database_type,
 		).into()
 	}
+
+	fn category(&self) -> core::BenchmarkCategory {
+		core::BenchmarkCategory::StorageWrite
+	}
 }
 
 struct TrieWriteBenchmark {
@@ -219,6 +219,15 @@ impl CloneableSpawn for TaskExecutor {
 	}
 }
 
+/// Configuration of a [`BenchDb`], as read by [`BenchDb::with_config_file`].
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BenchDbConfig {
+	database_type: String,
+	key_types: Option<String>,
+	keyring_length: usize,
+}
+
 impl BenchDb {
 	/// New immutable benchmarking database.
 	///
@@ -255,6 +264,35 @@ impl BenchDb {
 		Self::with_key_types(database_type, keyring_length, KeyTypes::Sr25519)
 	}
 
+	/// New immutable benchmarking database, configured by a TOML file.
+	///
+	/// The file supports the following keys:
+	///
+	/// - `database_type`: `"rocksdb"` or `"paritydb"`.
+	/// - `key_types`: `"sr25519"` (default) or `"ed25519"`.
+	/// - `keyring_length`: number of endowed accounts.
+	///
+	/// Returns an error if the file can't be read, contains unknown keys or invalid values.
+	pub fn with_config_file(path: &Path) -> Result<Self, String> {
+		let content = std::fs::read_to_string(path)
+			.map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+		let config: BenchDbConfig = toml::from_str(&content)
+			.map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+
+		let database_type = match config.database_type.as_str() {
+			"rocksdb" => DatabaseType::RocksDb,
+			"paritydb" => DatabaseType::ParityDb,
+			other => return Err(format!("Unknown database type: {}", other)),
+		};
+		let key_types = match config.key_types.as_ref().map(String::as_str) {
+			None | Some("sr25519") => KeyTypes::Sr25519,
+			Some("ed25519") => KeyTypes::Ed25519,
+			Some(other) => return Err(format!("Unknown key types: {}", other)),
+		};
+
+		Ok(Self::with_key_types(database_type, config.keyring_length, key_types))
+	}
+
 	// This should return client that is doing everything that full node
 	// is doing.
 	//
@@ -288,7 +326,9 @@ impl BenchDb {
 		(client, backend)
 	}
 
-	/// Generate new block using this database.
+	/// Generate new block on top of the best block of this database.
+	///
+	/// Signed transactions always use nonce `0`, so only the first block can contain them.
 	pub fn generate_block(&mut self, content: BlockContent) -> Block {
 		let (client, _backend) = Self::bench_client(
 			self.database_type,
@@ -297,8 +337,9 @@ impl BenchDb {
 			&self.keyring,
 		);
 
-		let runtime_version = client.runtime_version_at(&BlockId::number(0))
-			.expect("There should be runtime version at 0");
+		let best_number = client.chain_info().best_number;
+		let runtime_version = client.runtime_version_at(&BlockId::number(best_number))
+			.expect("There should be runtime version at the best block");
 
 		let genesis_hash = client.block_hash(Zero::zero())
 			.expect("Database error?")
@@ -309,7 +350,7 @@ impl BenchDb {
 			.new_block(Default::default())
 			.expect("Block creation failed");
 
-		let timestamp = 1 * MinimumPeriod::get();
+		let timestamp = (u64::from(best_number) + 1) * MinimumPeriod::get();
 
 		let mut inherent_data = InherentData::new();
 		inherent_data.put_data(sp_timestamp::INHERENT_IDENTIFIER, &timestamp)
@@ -319,7 +360,7 @@ impl BenchDb {
 
 		for extrinsic in client.runtime_api()
 			.inherent_extrinsics_with_context(
-				&BlockId::number(0),
+				&BlockId::number(best_number),
 				ExecutionContext::BlockConstruction,
 				inherent_data,
 			).expect("Get inherents failed")
@@ -395,6 +436,161 @@ impl BenchDb {
 		block
 	}
 
+	/// Import the given block into this database.
+	///
+	/// Unlike [`BenchContext::import_block`], this changes the seed database itself, so every
+	/// context created afterwards will contain the block.
+	pub fn import_block(&mut self, block: Block) -> Result<(), String> {
+		let (mut client, _backend) = Self::bench_client(
+			self.database_type,
+			self.directory_guard.path(),
+			Profile::Native,
+			&self.keyring,
+		);
+
+		Self::import_with(&mut client, &block)
+	}
+
+	/// Import the given block into this database and return how long the import took.
+	///
+	/// Like [`BenchDb::import_block`], this changes the seed database itself. Only the import
+	/// is timed, not the client setup.
+	pub fn replay_block(&mut self, block: &Block) -> Result<std::time::Duration, String> {
+		let (mut client, _backend) = Self::bench_client(
+			self.database_type,
+			self.directory_guard.path(),
+			Profile::Native,
+			&self.keyring,
+		);
+
+		let start = std::time::Instant::now();
+		Self::import_with(&mut client, block)?;
+		Ok(start.elapsed())
+	}
+
+	/// Import the given block with `client`, failing unless it is actually imported.
+	fn import_with(client: &mut Client, block: &Block) -> Result<(), String> {
+		match client.import_block(block_import_params(block), Default::default()) {
+			Ok(ImportResult::Imported(_)) => Ok(()),
+			Ok(result) => Err(format!("Block was not imported: {:?}", result)),
+			Err(err) => Err(format!("Failed to import block: {:?}", err)),
+		}
+	}
+
+	/// Create an independent copy of this database with the chain reverted to block `n`.
+	///
+	/// # Panics
+	///
+	/// Panics if `n` is above the best block, or if the blocks above `n` can't be reverted.
+	pub fn fork_at_block(&self, n: node_primitives::BlockNumber) -> BenchDb {
+		let fork = self.clone();
+		let (client, _backend) = Self::bench_client(
+			fork.database_type,
+			fork.directory_guard.path(),
+			Profile::Native,
+			&fork.keyring,
+		);
+
+		let best_number = client.chain_info().best_number;
+		assert!(n <= best_number, "Block #{} is above the best block #{}", n, best_number);
+		let reverted = client.revert(best_number - n).expect("Failed to revert blocks");
+		assert_eq!(reverted, best_number - n, "Not all blocks above #{} were reverted", n);
+
+		fork
+	}
+
+	/// Check the state root of the best block in this database.
+	///
+	/// The root stored in the header is compared against `expected_root` when it is given,
+	/// otherwise against the root recomputed from all top level values of the state.
+	///
+	/// # Panics
+	///
+	/// Panics if the state root does not match.
+	pub fn assert_storage_consistent(&self, expected_root: Option<sp_core::H256>) {
+		let (client, _backend) = Self::bench_client(
+			self.database_type,
+			self.directory_guard.path(),
+			Profile::Native,
+			&self.keyring,
+		);
+
+		assert_storage_consistent(&client, expected_root);
+	}
+
+	/// Write the top level state of the best block to the file at `path`.
+	///
+	/// The file contains one `{"key": "0x...", "value": "0x..."}` JSON object per line, sorted
+	/// by key.
+	pub fn export_state(&self, path: &Path) -> Result<(), String> {
+		use sc_client_api::backend::StateBackend;
+		use std::io::Write;
+
+		fn to_hex(bytes: &[u8]) -> String {
+			bytes.iter().map(|b| format!("{:02x}", b)).collect()
+		}
+
+		let (client, _backend) = Self::bench_client(
+			self.database_type,
+			self.directory_guard.path(),
+			Profile::Native,
+			&self.keyring,
+		);
+
+		let best_hash = client.chain_info().best_hash;
+		let mut pairs = client.state_at(&BlockId::Hash(best_hash))
+			.map_err(|e| format!("Failed to get state: {:?}", e))?
+			.pairs();
+		pairs.sort();
+
+		let file = std::fs::File::create(path)
+			.map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+		let mut writer = std::io::BufWriter::new(file);
+		for (key, value) in pairs {
+			writeln!(
+				writer,
+				"{{\"key\": \"0x{}\", \"value\": \"0x{}\"}}",
+				to_hex(&key),
+				to_hex(&value),
+			).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+		}
+		writer.flush().map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+	}
+
+	/// Drop all blocks and state of this database, leaving only a freshly initialized genesis.
+	///
+	/// The keyring is kept, so the genesis state is the same as when the database was created.
+	pub fn wipe_storage(&mut self) {
+		let dir = tempfile::tempdir().expect("temp dir creation failed");
+		log::trace!(
+			target: "bench-logistics",
+			"Recreated seed db at {}",
+			dir.path().to_string_lossy(),
+		);
+		let (_client, _backend) = Self::bench_client(
+			self.database_type,
+			dir.path(),
+			Profile::Native,
+			&self.keyring,
+		);
+		self.directory_guard = Guard(dir);
+	}
+
+	/// Total size in bytes of the files of this database.
+	pub fn storage_size_bytes(&self) -> Result<u64, std::io::Error> {
+		fn dir_size(path: &Path) -> Result<u64, std::io::Error> {
+			let mut size = 0;
+			for entry in std::fs::read_dir(path)? {
+				let entry = entry?;
+				let metadata = entry.metadata()?;
+				size += if metadata.is_dir() { dir_size(&entry.path())? } else { metadata.len() };
+			}
+			Ok(size)
+		}
+
+		dir_size(self.directory_guard.path())
+	}
+
 	/// Database path.
 	pub fn path(&self) -> &Path {
 		self.directory_guard.path()
@@ -552,6 +748,36 @@ pub struct BenchContext {
 
 type AccountPublic = <Signature as Verify>::Signer;
 
+/// Parameters to import `block` as if it was received from the network.
+fn block_import_params<Transaction>(block: &Block) -> BlockImportParams<Block, Transaction> {
+	let mut import_params = BlockImportParams::new(BlockOrigin::NetworkBroadcast, block.header.clone());
+	import_params.body = Some(block.extrinsics().to_vec());
+	import_params.fork_choice = Some(ForkChoiceStrategy::LongestChain);
+	import_params
+}
+
+/// Check the state root of the best block known to `client`.
+///
+/// See [`BenchDb::assert_storage_consistent`].
+fn assert_storage_consistent(client: &Client, expected_root: Option<sp_core::H256>) {
+	use sc_client_api::backend::StateBackend;
+	use sp_runtime::traits::{BlakeTwo256, Hash as HashT};
+
+	let best_hash = client.chain_info().best_hash;
+	let root = client.header(&BlockId::Hash(best_hash))
+		.expect("Database error?")
+		.expect("Best block header always exists; qed")
+		.state_root;
+
+	let expected_root = expected_root.unwrap_or_else(|| {
+		let pairs = client.state_at(&BlockId::Hash(best_hash))
+			.expect("Best block state always exists; qed")
+			.pairs();
+		BlakeTwo256::trie_root(pairs)
+	});
+	assert_eq!(root, expected_root, "Unexpected storage root");
+}
+
 fn get_from_seed<TPublic: Public>(seed: &str) -> <TPublic::Pair as Pair>::Public {
 	TPublic::Pair::from_string(&format!("//{}", seed), None)
 		.expect("static values are valid; qed")
@@ -568,14 +794,10 @@ where
 impl BenchContext {
 	/// Import some block.
 	pub fn import_block(&mut self, block: Block) {
-		let mut import_params = BlockImportParams::new(BlockOrigin::NetworkBroadcast, block.header.clone());
-		import_params.body = Some(block.extrinsics().to_vec());
-		import_params.fork_choice = Some(ForkChoiceStrategy::LongestChain);
-
 		assert_eq!(self.client.chain_info().best_number, 0);
 
 		assert_eq!(
-			self.client.import_block(import_params, Default::default())
+			self.client.import_block(block_import_params(&block), Default::default())
 				.expect("Failed to import block"),
 			ImportResult::Imported(
 				ImportedAux {
@@ -592,8 +814,153 @@ impl BenchContext {
 		assert_eq!(self.client.chain_info().best_number, 1);
 	}
 
+	/// Check the state root of the best block in the database of this context.
+	///
+	/// See [`BenchDb::assert_storage_consistent`].
+	pub fn assert_storage_consistent(&self, expected_root: Option<sp_core::H256>) {
+		assert_storage_consistent(&self.client, expected_root);
+	}
+
 	/// Database path for the current context.
 	pub fn path(&self) -> &Path {
 		self.db_guard.path()
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn genesis_storage_is_consistent() {
+		let bench_db = BenchDb::new(DatabaseType::RocksDb, 10);
+		bench_db.assert_storage_consistent(None);
+	}
+
+	#[test]
+	fn context_storage_is_consistent() {
+		let bench_db = BenchDb::new(DatabaseType::RocksDb, 10);
+		let context = bench_db.create_context(Profile::Native);
+		context.assert_storage_consistent(None);
+	}
+
+	#[test]
+	fn import_block_works() {
+		let mut bench_db = BenchDb::new(DatabaseType::RocksDb, 10);
+		let block = bench_db.generate_block(BlockType::RandomTransfersKeepAlive.to_content(Some(2)));
+
+		bench_db.import_block(block.clone()).expect("Failed to import block");
+		assert_eq!(bench_db.create_context(Profile::Native).client.chain_info().best_number, 1);
+		bench_db.assert_storage_consistent(None);
+
+		assert!(bench_db.import_block(block).is_err());
+	}
+
+	#[test]
+	fn replay_block_works() {
+		let mut bench_db = BenchDb::new(DatabaseType::RocksDb, 10);
+		let block = bench_db.generate_block(BlockType::RandomTransfersKeepAlive.to_content(Some(2)));
+
+		let mut fresh_db = bench_db.clone();
+		bench_db.import_block(block.clone()).expect("Failed to import block");
+
+		assert!(fresh_db.replay_block(&block).is_ok());
+		assert_eq!(fresh_db.create_context(Profile::Native).client.chain_info().best_number, 1);
+		fresh_db.assert_storage_consistent(None);
+	}
+
+	#[test]
+	fn fork_at_block_works() {
+		let mut bench_db = BenchDb::new(DatabaseType::RocksDb, 10);
+		for _ in 0..3 {
+			let block = bench_db.generate_block(BlockType::Noop.to_content(Some(0)));
+			bench_db.import_block(block).expect("Failed to import block");
+		}
+
+		let fork = bench_db.fork_at_block(1);
+		assert_eq!(fork.create_context(Profile::Native).client.chain_info().best_number, 1);
+		fork.assert_storage_consistent(None);
+
+		assert_eq!(bench_db.create_context(Profile::Native).client.chain_info().best_number, 3);
+		bench_db.assert_storage_consistent(None);
+	}
+
+	#[test]
+	fn with_config_file_works() {
+		let dir = tempfile::tempdir().expect("temp dir creation failed");
+		let path = dir.path().join("bench.toml");
+		std::fs::write(
+			&path,
+			"database_type = \"rocksdb\"\nkey_types = \"ed25519\"\nkeyring_length = 10\n",
+		).expect("Failed to write config");
+
+		let bench_db = BenchDb::with_config_file(&path).expect("Config is valid");
+		assert_eq!(bench_db.database_type, DatabaseType::RocksDb);
+		assert_eq!(
+			bench_db.keyring.collect_account_ids(),
+			BenchKeyring::new(10, KeyTypes::Ed25519).collect_account_ids(),
+		);
+		bench_db.assert_storage_consistent(None);
+	}
+
+	#[test]
+	fn with_config_file_rejects_unknown_keys() {
+		let dir = tempfile::tempdir().expect("temp dir creation failed");
+		let path = dir.path().join("bench.toml");
+		std::fs::write(
+			&path,
+			"database_type = \"rocksdb\"\nkeyring_length = 10\ncache_size = 512\n",
+		).expect("Failed to write config");
+
+		assert!(BenchDb::with_config_file(&path).is_err());
+	}
+
+	#[test]
+	fn export_state_works() {
+		let bench_db = BenchDb::new(DatabaseType::RocksDb, 10);
+		let dir = tempfile::tempdir().expect("temp dir creation failed");
+		let path = dir.path().join("state.json");
+		bench_db.export_state(&path).expect("Failed to export state");
+
+		let content = std::fs::read_to_string(&path).expect("Failed to read exported state");
+		let lines = content.lines().collect::<Vec<_>>();
+		assert!(lines.iter().all(|line|
+			line.starts_with("{\"key\": \"0x") && line.ends_with("\"}")
+		));
+		// `:code` is always part of the genesis state.
+		assert!(lines.iter().any(|line| line.starts_with("{\"key\": \"0x3a636f6465\",")));
+
+		let mut sorted = lines.clone();
+		sorted.sort();
+		assert_eq!(lines, sorted);
+	}
+
+	#[test]
+	fn wipe_storage_works() {
+		let dir = tempfile::tempdir().expect("temp dir creation failed");
+		let genesis_path = dir.path().join("genesis.json");
+		let wiped_path = dir.path().join("wiped.json");
+
+		let mut bench_db = BenchDb::new(DatabaseType::RocksDb, 10);
+		bench_db.export_state(&genesis_path).expect("Failed to export state");
+
+		let block = bench_db.generate_block(BlockType::RandomTransfersKeepAlive.to_content(Some(2)));
+		bench_db.import_block(block).expect("Failed to import block");
+
+		bench_db.wipe_storage();
+		assert_eq!(bench_db.create_context(Profile::Native).client.chain_info().best_number, 0);
+
+		// Wiping leaves a freshly initialized genesis, not an empty state.
+		bench_db.export_state(&wiped_path).expect("Failed to export state");
+		assert_eq!(
+			std::fs::read_to_string(&wiped_path).expect("Failed to read exported state"),
+			std::fs::read_to_string(&genesis_path).expect("Failed to read exported state"),
+		);
+	}
+
+	#[test]
+	fn storage_size_is_not_zero_after_genesis() {
+		let bench_db = BenchDb::new(DatabaseType::RocksDb, 10);
+		assert!(bench_db.storage_size_bytes().expect("Failed to read database size") > 0);
+	}
+}
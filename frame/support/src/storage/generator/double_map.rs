@@ -415,6 +415,43 @@ impl<
 			}
 		}
 	}
+
+	fn translate_keys_and_values<O1, O2, OV, F>(mut f: F) where
+		O1: Decode,
+		O2: Decode,
+		OV: Decode,
+		F: FnMut(O1, O2, OV) -> Option<(K1, K2, V)>,
+	{
+		let prefix = G::prefix_hash();
+		let mut previous_key = prefix.clone();
+		// New keys can be placed anywhere in the map, so all the old elements are removed
+		// before inserting any translated one.
+		let mut old_elements = Vec::new();
+		while let Some(next) = sp_io::storage::next_key(&previous_key)
+			.filter(|n| n.starts_with(&prefix))
+		{
+			previous_key = next;
+			let decoded = unhashed::get_raw(&previous_key).and_then(|raw_value| {
+				let mut k1_k2_material = G::Hasher1::reverse(&previous_key[prefix.len()..]);
+				let k1 = O1::decode(&mut k1_k2_material).ok()?;
+				let mut k2_material = G::Hasher2::reverse(k1_k2_material);
+				let k2 = O2::decode(&mut k2_material).ok()?;
+				let value = OV::decode(&mut &raw_value[..]).ok()?;
+				Some((k1, k2, value))
+			});
+
+			if let Some(element) = decoded {
+				unhashed::kill(&previous_key);
+				old_elements.push(element);
+			}
+		}
+
+		for (k1, k2, value) in old_elements {
+			if let Some((k1, k2, value)) = f(k1, k2, value) {
+				unhashed::put::<V>(&G::storage_double_map_final_key(k1, k2), &value);
+			}
+		}
+	}
 }
 
 /// Test iterators for StorageDoubleMap
@@ -524,4 +561,38 @@ mod test_iterators {
 			assert_eq!(unhashed::get(&key_after_prefix(prefix.clone())), Some(1u64));
 		})
 	}
+
+	#[test]
+	fn double_map_range_scan_k2() {
+		sp_io::TestExternalities::default().execute_with(|| {
+			for i in 0..10 {
+				DoubleMap::insert(1, i as u32, i as u64);
+				DoubleMap::insert(2, i as u32, i as u64);
+			}
+
+			let mut in_range = DoubleMap::range_scan_k2(1, 3, 6).collect::<Vec<_>>();
+			in_range.sort();
+			assert_eq!(in_range, vec![(3, 3), (4, 4), (5, 5), (6, 6)]);
+
+			assert_eq!(DoubleMap::range_scan_k2(3, 0, 10).collect::<Vec<_>>(), vec![]);
+		})
+	}
+
+	#[test]
+	fn double_map_translate_keys_and_values() {
+		sp_io::TestExternalities::default().execute_with(|| {
+			for i in 0..4 {
+				DoubleMap::insert(i as u16, i as u32, i as u64);
+			}
+
+			// Shift the second keys, so that new keys collide with old ones, and drop element 3.
+			DoubleMap::translate_keys_and_values(|k1: u16, k2: u32, v: u64| {
+				if k1 == 3 { None } else { Some((k1, k2 + 1, v * 10)) }
+			});
+
+			let mut elements = DoubleMap::iter().collect::<Vec<_>>();
+			elements.sort();
+			assert_eq!(elements, vec![(0, 1, 0), (1, 2, 10), (2, 3, 20)]);
+		})
+	}
 }
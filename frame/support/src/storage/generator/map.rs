@@ -227,6 +227,18 @@ impl<K: FullEncode, V: FullCodec, G: StorageMap<K, V>> storage::StorageMap<K, V>
 		unhashed::put(Self::storage_map_final_key(key).as_ref(), &val)
 	}
 
+	fn batch_insert<KeyArg, ValArg, I>(entries: I)
+	where
+		KeyArg: EncodeLike<K>,
+		ValArg: EncodeLike<V>,
+		I: IntoIterator<Item = (KeyArg, ValArg)>,
+	{
+		// There is no batched write in the runtime interface, every entry is written on its own.
+		for (key, val) in entries {
+			unhashed::put(Self::storage_map_final_key(key).as_ref(), &val)
+		}
+	}
+
 	fn remove<KeyArg: EncodeLike<K>>(key: KeyArg) {
 		unhashed::kill(Self::storage_map_final_key(key).as_ref())
 	}
@@ -273,6 +285,22 @@ impl<K: FullEncode, V: FullCodec, G: StorageMap<K, V>> storage::StorageMap<K, V>
 		ret
 	}
 
+	fn try_mutate_exists_and_get<KeyArg, R, E, F>(key: KeyArg, f: F) -> Result<(R, Option<V>), E>
+	where
+		KeyArg: EncodeLike<K>,
+		F: FnOnce(&mut Option<V>) -> Result<R, E>,
+	{
+		let final_key = Self::storage_map_final_key(key);
+		let mut val = unhashed::get(final_key.as_ref());
+
+		let ret = f(&mut val)?;
+		match val {
+			Some(ref val) => unhashed::put(final_key.as_ref(), &val.borrow()),
+			None => unhashed::kill(final_key.as_ref()),
+		}
+		Ok((ret, val))
+	}
+
 	fn take<KeyArg: EncodeLike<K>>(key: KeyArg) -> Self::Query {
 		let key = Self::storage_map_final_key(key);
 		let value = unhashed::take(key.as_ref());
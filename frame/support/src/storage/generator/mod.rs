@@ -60,6 +60,7 @@ mod tests {
 			Value get(fn value) config(): (u64, u64);
 			NumberMap: map hasher(identity) u32 => u64;
 			DoubleMap: double_map hasher(identity) u32, hasher(identity) u32 => u64;
+			AppendableDM: double_map hasher(identity) u32, hasher(identity) u32 => Vec<u32>;
 		}
 	}
 
@@ -82,6 +83,18 @@ mod tests {
 		})
 	}
 
+	#[test]
+	fn value_try_put_works() {
+		let t = GenesisConfig::default().build_storage().unwrap();
+		TestExternalities::new(t).execute_with(|| {
+			assert_eq!(Value::try_put((1, 2), 15), Err(crate::storage::StorageError::ValueTooLarge));
+			assert_eq!(Value::get(), (0, 0));
+
+			assert_eq!(Value::try_put((1, 2), 16), Ok(()));
+			assert_eq!(Value::get(), (1, 2));
+		})
+	}
+
 	#[test]
 	fn map_translate_works() {
 		let t = GenesisConfig::default().build_storage().unwrap();
@@ -106,6 +119,19 @@ mod tests {
 		})
 	}
 
+	#[test]
+	fn map_batch_insert_works() {
+		let t = GenesisConfig::default().build_storage().unwrap();
+		TestExternalities::new(t).execute_with(|| {
+			NumberMap::batch_insert((0u32..10u32).map(|i| (i, i as u64 * 2)));
+
+			assert_eq!(
+				NumberMap::iter().collect::<Vec<_>>(),
+				(0..10).map(|x| (x as u32, x as u64 * 2)).collect::<Vec<_>>(),
+			);
+		})
+	}
+
 	#[test]
 	fn try_mutate_works() {
 		let t = GenesisConfig::default().build_storage().unwrap();
@@ -155,4 +181,102 @@ mod tests {
 			assert_eq!(DoubleMap::get(0, 0), 6);
 		});
 	}
+
+	#[test]
+	fn try_mutate_exists_and_get_works() {
+		let t = GenesisConfig::default().build_storage().unwrap();
+		TestExternalities::new(t).execute_with(|| {
+			assert_noop!(
+				NumberMap::try_mutate_exists_and_get(0, |value| -> Result<(), &'static str> {
+					*value = Some(4);
+					Err("don't change value")
+				}),
+				"don't change value",
+			);
+			assert!(!NumberMap::contains_key(0));
+
+			assert_eq!(
+				NumberMap::try_mutate_exists_and_get(0, |value| -> Result<bool, ()> {
+					let existed = value.is_some();
+					*value = Some(4);
+					Ok(existed)
+				}),
+				Ok((false, Some(4))),
+			);
+			assert_eq!(NumberMap::get(0), 4);
+
+			assert_eq!(
+				NumberMap::try_mutate_exists_and_get(0, |value| -> Result<bool, ()> {
+					let existed = value.is_some();
+					*value = None;
+					Ok(existed)
+				}),
+				Ok((true, None)),
+			);
+			assert!(!NumberMap::contains_key(0));
+		});
+	}
+
+	#[test]
+	fn map_remove_and_collect_works() {
+		let t = GenesisConfig::default().build_storage().unwrap();
+		TestExternalities::new(t).execute_with(|| {
+			NumberMap::batch_insert((0u32..10u32).map(|i| (i, i as u64)));
+
+			let mut removed = NumberMap::remove_and_collect(|k| k % 2 == 0).collect::<Vec<_>>();
+			removed.sort();
+			assert_eq!(removed, (0..5u32).map(|x| (x * 2, x as u64 * 2)).collect::<Vec<_>>());
+
+			assert_eq!(
+				NumberMap::iter().collect::<Vec<_>>(),
+				(0..5u32).map(|x| (x * 2 + 1, x as u64 * 2 + 1)).collect::<Vec<_>>(),
+			);
+
+			assert_eq!(NumberMap::remove_and_collect(|_| false).count(), 0);
+			assert_eq!(NumberMap::iter().count(), 5);
+		});
+	}
+
+	#[test]
+	fn map_pop_largest_and_smallest_works() {
+		let t = GenesisConfig::default().build_storage().unwrap();
+		TestExternalities::new(t).execute_with(|| {
+			assert_eq!(NumberMap::pop_largest(), None);
+			assert_eq!(NumberMap::pop_smallest(), None);
+
+			NumberMap::batch_insert(vec![(3, 30), (1, 10), (7, 70), (5, 50)]);
+
+			assert_eq!(NumberMap::pop_largest(), Some((7, 70)));
+			assert_eq!(NumberMap::pop_smallest(), Some((1, 10)));
+			assert!(!NumberMap::contains_key(7));
+			assert!(!NumberMap::contains_key(1));
+			assert_eq!(NumberMap::iter().collect::<Vec<_>>(), vec![(3, 30), (5, 50)]);
+
+			assert_eq!(NumberMap::pop_smallest(), Some((3, 30)));
+			assert_eq!(NumberMap::pop_largest(), Some((5, 50)));
+			assert_eq!(NumberMap::pop_largest(), None);
+		});
+	}
+
+	#[test]
+	fn double_map_try_append_works() {
+		let t = GenesisConfig::default().build_storage().unwrap();
+		TestExternalities::new(t).execute_with(|| {
+			// a missing value counts as empty.
+			assert_eq!(AppendableDM::try_append(1, 1, 5u32, 2), Ok(()));
+			assert_eq!(AppendableDM::get(1, 1), vec![5]);
+
+			// below the bound.
+			assert_eq!(AppendableDM::try_append(1, 1, 6u32, 2), Ok(()));
+			assert_eq!(AppendableDM::get(1, 1), vec![5, 6]);
+
+			// at the bound the storage is left untouched.
+			assert_noop!(AppendableDM::try_append(1, 1, 7u32, 2), ());
+			assert_eq!(AppendableDM::get(1, 1), vec![5, 6]);
+
+			// a bound of zero rejects even a missing value.
+			assert_noop!(AppendableDM::try_append(1, 2, 7u32, 0), ());
+			assert!(!AppendableDM::contains_key(1, 2));
+		});
+	}
 }
@@ -195,3 +195,66 @@ pub fn take_storage_item<K: Encode + Sized, T: Decode + Sized, H: StorageHasher>
 ) -> Option<T> {
 	take_storage_value(module, item, key.using_encoded(H::hash).as_ref())
 }
+
+/// Move all the values of the storage item `item` from the pallet `old_pallet` to the pallet
+/// `new_pallet`.
+///
+/// Every key under `twox_128(old_pallet) ++ twox_128(item)` is removed and its value is put
+/// under the same key with the `twox_128(new_pallet) ++ twox_128(item)` prefix instead.
+pub fn move_storage_from_pallet(item: &[u8], old_pallet: &[u8], new_pallet: &[u8]) {
+	if old_pallet == new_pallet {
+		return;
+	}
+
+	let mut old_prefix = Vec::new();
+	old_prefix.extend_from_slice(&Twox128::hash(old_pallet));
+	old_prefix.extend_from_slice(&Twox128::hash(item));
+	let mut new_prefix = Vec::new();
+	new_prefix.extend_from_slice(&Twox128::hash(new_pallet));
+	new_prefix.extend_from_slice(&Twox128::hash(item));
+
+	let move_value = |old_key: &[u8]| {
+		if let Some(value) = frame_support::storage::unhashed::get_raw(old_key) {
+			let mut new_key = new_prefix.clone();
+			new_key.extend_from_slice(&old_key[old_prefix.len()..]);
+			frame_support::storage::unhashed::put_raw(&new_key, &value);
+			frame_support::storage::unhashed::kill(old_key);
+		}
+	};
+
+	// The prefix itself is the key of a plain storage value.
+	move_value(&old_prefix);
+
+	let mut previous_key = old_prefix.clone();
+	while let Some(next) = sp_io::storage::next_key(&previous_key)
+		.filter(|n| n.starts_with(&old_prefix))
+	{
+		move_value(&next);
+		previous_key = next;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn move_storage_from_pallet_works() {
+		sp_io::TestExternalities::default().execute_with(|| {
+			put_storage_value(b"Old", b"Item", &[], 1u32);
+			put_storage_value(b"Old", b"Item", &[1], 2u32);
+			put_storage_value(b"Old", b"Item", &[2, 2], 3u32);
+			put_storage_value(b"Old", b"Other", &[1], 4u32);
+
+			move_storage_from_pallet(b"Item", b"Old", b"New");
+
+			assert!(!have_storage_value(b"Old", b"Item", &[]));
+			assert_eq!(StorageIterator::<u32>::new(b"Old", b"Item").count(), 0);
+			assert_eq!(get_storage_value::<u32>(b"New", b"Item", &[]), Some(1));
+			let mut moved = StorageIterator::<u32>::new(b"New", b"Item").collect::<Vec<_>>();
+			moved.sort();
+			assert_eq!(moved, vec![(vec![1], 2), (vec![2, 2], 3)]);
+			assert_eq!(get_storage_value::<u32>(b"Old", b"Other", &[1]), Some(4));
+		})
+	}
+}
@@ -29,6 +29,13 @@ pub mod child;
 pub mod generator;
 pub mod migration;
 
+/// An error returned by fallible storage writes.
+#[derive(Clone, Copy, PartialEq, Eq, crate::RuntimeDebug)]
+pub enum StorageError {
+	/// The encoded value is larger than the allowed maximum.
+	ValueTooLarge,
+}
+
 /// A trait for working with macro-generated storage values under the substrate storage API.
 ///
 /// Details on implementation can be found at
@@ -75,6 +82,19 @@ pub trait StorageValue<T: FullCodec> {
 	/// Store a value under this key into the provided storage instance.
 	fn put<Arg: EncodeLike<T>>(val: Arg);
 
+	/// Store a value under this key into the provided storage instance if its encoding is at
+	/// most `max_bytes` long.
+	///
+	/// Returns `Err(StorageError::ValueTooLarge)` and leaves the storage untouched otherwise.
+	fn try_put<Arg: EncodeLike<T>>(val: Arg, max_bytes: u32) -> Result<(), StorageError> {
+		let bytes = val.encode();
+		if bytes.len() > max_bytes as usize {
+			return Err(StorageError::ValueTooLarge);
+		}
+		unhashed::put_raw(&Self::hashed_key(), &bytes);
+		Ok(())
+	}
+
 	/// Store a value under this key into the provided storage instance; this uses the query
 	/// type rather than the underlying value.
 	fn set(val: Self::Query);
@@ -145,6 +165,16 @@ pub trait StorageMap<K: FullEncode, V: FullCodec> {
 	/// Store a value to be associated with the given key from the map.
 	fn insert<KeyArg: EncodeLike<K>, ValArg: EncodeLike<V>>(key: KeyArg, val: ValArg);
 
+	/// Store all the given key-value pairs into the map.
+	///
+	/// This is the write-path counterpart of reading multiple keys; it has the same effect as
+	/// calling [`insert`](#tymethod.insert) for each entry in order.
+	fn batch_insert<KeyArg, ValArg, I>(entries: I)
+	where
+		KeyArg: EncodeLike<K>,
+		ValArg: EncodeLike<V>,
+		I: IntoIterator<Item = (KeyArg, ValArg)>;
+
 	/// Remove the value under a key.
 	fn remove<KeyArg: EncodeLike<K>>(key: KeyArg);
 
@@ -166,6 +196,14 @@ pub trait StorageMap<K: FullEncode, V: FullCodec> {
 		f: F,
 	) -> Result<R, E>;
 
+	/// Mutate the item, only if an `Ok` value is returned. Deletes the item if mutated to a `None`.
+	///
+	/// Returns the result of `f` along with the new value, without reading the storage again.
+	fn try_mutate_exists_and_get<KeyArg, R, E, F>(key: KeyArg, f: F) -> Result<(R, Option<V>), E>
+	where
+		KeyArg: EncodeLike<K>,
+		F: FnOnce(&mut Option<V>) -> Result<R, E>;
+
 	/// Take the value under a key.
 	fn take<KeyArg: EncodeLike<K>>(key: KeyArg) -> Self::Query;
 
@@ -232,6 +270,38 @@ pub trait IterableStorageMap<K: FullEncode, V: FullCodec>: StorageMap<K, V> {
 	/// Translate the values of all elements by a function `f`, in the map in no particular order.
 	/// By returning `None` from `f` for an element, you'll remove it from the map.
 	fn translate<O: Decode, F: Fn(K, O) -> Option<V>>(f: F);
+
+	/// Remove all elements from the map whose key matches `predicate` and iterate through them
+	/// in no particular order.
+	///
+	/// The matching elements are all removed before the iterator is returned.
+	fn remove_and_collect<F: Fn(&K) -> bool>(predicate: F) -> sp_std::vec::IntoIter<(K, V)> {
+		let removed = Self::iter().filter(|(k, _)| predicate(k)).collect::<Vec<_>>();
+		for (k, _) in removed.iter() {
+			Self::remove(k);
+		}
+		removed.into_iter()
+	}
+
+	/// Remove the element with the largest key from the map and return it.
+	///
+	/// As the keys are hashed, the storage order does not follow the order of `K`, so all the
+	/// elements of the map are visited.
+	fn pop_largest() -> Option<(K, V)> where K: Ord {
+		let (key, value) = Self::iter().max_by(|a, b| a.0.cmp(&b.0))?;
+		Self::remove(&key);
+		Some((key, value))
+	}
+
+	/// Remove the element with the smallest key from the map and return it.
+	///
+	/// As the keys are hashed, the storage order does not follow the order of `K`, so all the
+	/// elements of the map are visited.
+	fn pop_smallest() -> Option<(K, V)> where K: Ord {
+		let (key, value) = Self::iter().min_by(|a, b| a.0.cmp(&b.0))?;
+		Self::remove(&key);
+		Some((key, value))
+	}
 }
 
 /// A strongly-typed double map in storage whose secondary keys and values can be iterated over.
@@ -256,6 +326,23 @@ pub trait IterableStorageDoubleMap<
 	/// you'll get undefined results.
 	fn drain_prefix(k1: impl EncodeLike<K1>) -> Self::PrefixIterator;
 
+	/// Enumerate all elements in the map with first key `k1` and a second key in the inclusive
+	/// range `[k2_start, k2_end]`, in no particular order.
+	///
+	/// As the second keys are hashed, the storage order does not follow the order of `K2`, so
+	/// all the entries with first key `k1` are visited.
+	fn range_scan_k2(
+		k1: impl EncodeLike<K1>,
+		k2_start: K2,
+		k2_end: K2,
+	) -> KeyRangeIterator<Self::PrefixIterator, K2> where K2: Ord {
+		KeyRangeIterator {
+			inner: Self::iter_prefix(k1),
+			start: k2_start,
+			end: k2_end,
+		}
+	}
+
 	/// Enumerate all elements in the map in no particular order. If you add or remove values to
 	/// the map while doing this, you'll get undefined results.
 	fn iter() -> Self::Iterator;
@@ -267,6 +354,18 @@ pub trait IterableStorageDoubleMap<
 	/// Translate the values of all elements by a function `f`, in the map in no particular order.
 	/// By returning `None` from `f` for an element, you'll remove it from the map.
 	fn translate<O: Decode, F: Fn(O) -> Option<V>>(f: F);
+
+	/// Translate the keys and values of all elements by a function `f`, in the map in no
+	/// particular order.
+	///
+	/// Each element is decoded with the old key types `O1`, `O2` and the old value type `OV`,
+	/// then stored under the keys returned by `f`. By returning `None` from `f` for an element,
+	/// you'll remove it from the map. Elements that fail to decode are left untouched.
+	fn translate_keys_and_values<O1, O2, OV, F>(f: F) where
+		O1: Decode,
+		O2: Decode,
+		OV: Decode,
+		F: FnMut(O1, O2, OV) -> Option<(K1, K2, V)>;
 }
 
 /// An implementation of a map with a two keys.
@@ -387,6 +486,36 @@ pub trait StorageDoubleMap<K1: FullEncode, K2: FullEncode, V: FullCodec> {
 		V::decode_len(&Self::hashed_key_for(key1, key2))
 	}
 
+	/// Append the given item to the value in the storage, unless the value already holds
+	/// `max_len` items or more.
+	///
+	/// The length is read without decoding the entire value, see [`decode_len`]. A value that
+	/// does not exist or fails to decode its length counts as empty.
+	///
+	/// Returns `Err(())` and leaves the storage untouched if the bound would be exceeded.
+	///
+	/// [`decode_len`]: #method.decode_len
+	fn try_append<Item, EncodeLikeItem, KArg1, KArg2>(
+		k1: KArg1,
+		k2: KArg2,
+		item: EncodeLikeItem,
+		max_len: usize,
+	) -> Result<(), ()>
+	where
+		KArg1: EncodeLike<K1>,
+		KArg2: EncodeLike<K2>,
+		Item: Encode,
+		EncodeLikeItem: EncodeLike<Item>,
+		V: StorageAppend<Item> + StorageDecodeLength,
+	{
+		let key = Self::hashed_key_for(k1, k2);
+		if V::decode_len(&key).unwrap_or(0) >= max_len {
+			return Err(());
+		}
+		sp_io::storage::append(&key, item.encode());
+		Ok(())
+	}
+
 	/// Migrate an item with the given `key1` and `key2` from defunct `OldHasher1` and
 	/// `OldHasher2` to the current hashers.
 	///
@@ -432,6 +561,29 @@ impl<Value: Decode> Iterator for PrefixIterator<Value> {
 	}
 }
 
+/// Iterator over the `(key, value)` pairs of an inner iterator whose key is in an inclusive range.
+pub struct KeyRangeIterator<Iter, Key> {
+	inner: Iter,
+	start: Key,
+	end: Key,
+}
+
+impl<Iter, Key, Value> Iterator for KeyRangeIterator<Iter, Key> where
+	Iter: Iterator<Item = (Key, Value)>,
+	Key: Ord,
+{
+	type Item = (Key, Value);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let (key, value) = self.inner.next()?;
+			if key >= self.start && key <= self.end {
+				return Some((key, value))
+			}
+		}
+	}
+}
+
 /// Trait for maps that store all its value after a unique prefix.
 ///
 /// By default the final prefix is:
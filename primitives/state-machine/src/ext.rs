@@ -430,11 +430,11 @@ where
 		self.mark_dirty();
 
 		let backend = &mut self.backend;
-		let current_value = self.overlay.value_mut_or_insert_with(
+		self.overlay.append_storage(
 			&key,
+			value,
 			|| backend.storage(&key).expect(EXT_NOT_ALLOWED_TO_FAIL).unwrap_or_default()
 		);
-		StorageAppend::new(current_value).append(value);
 	}
 
 	fn chain_id(&self) -> u64 {
@@ -67,8 +67,13 @@ pub use changes_trie::{
 };
 pub use overlayed_changes::{
 	OverlayedChanges, StorageChanges, StorageTransactionCache, StorageKey, StorageValue,
-	StorageCollection, ChildStorageCollection,
+	StorageCollection, ChildStorageCollection, OverlayedChangeSet, OverlayedValue,
+	OverlayedEntry, OccupiedEntry, VacantEntry, CommittedView, UncommittedView, ExtrinsicScope,
+	TransactionBudget, BudgetExhausted, DoubleWriteError, SealedChangeSetError,
+	TransactionSummary, TransactionEvent,
 };
+#[cfg(feature = "audit")]
+pub use overlayed_changes::AuditEntry;
 pub use proving_backend::{
 	create_proof_check_backend, ProofRecorder, ProvingBackend, ProvingBackendRecorder,
 };
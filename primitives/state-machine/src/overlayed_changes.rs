@@ -26,12 +26,18 @@ use crate::{
 	stats::StateMachineStats,
 };
 
-use std::{mem, ops, collections::{HashMap, BTreeMap, BTreeSet}};
+use std::{fmt, mem, ops, sync::Arc, collections::{HashMap, HashSet, BTreeMap, BTreeSet, btree_map}};
+#[cfg(feature = "profiling")]
+use std::cell::RefCell;
 use codec::{Decode, Encode};
-use sp_core::storage::{well_known_keys::EXTRINSIC_INDEX, ChildInfo, ChildType};
+use sp_core::storage::{
+	well_known_keys::EXTRINSIC_INDEX, ChildInfo, ChildType, Storage, StorageChild,
+};
 use sp_core::offchain::storage::OffchainOverlayedChanges;
+use sp_core::hexdisplay::HexDisplay;
 
 use hash_db::Hasher;
+use sp_trie::{MemoryDB, StorageProof, TrieMut, trie_types::TrieDBMut};
 
 /// Storage key.
 pub type StorageKey = Vec<u8>;
@@ -57,8 +63,48 @@ pub struct OverlayedChanges {
 	committed: OverlayedChangeSet,
 	/// True if extrinsics stats must be collected.
 	collect_extrinsics: bool,
+	/// Index of the extrinsic executed by [`apply_extrinsic`], if any.
+	///
+	/// [`apply_extrinsic`]: #method.apply_extrinsic
+	applied_extrinsic: Option<u32>,
 	/// Collect statistic on this execution.
 	stats: StateMachineStats,
+	/// Top level keys merged by the last commit, if tracking is enabled.
+	last_commit_keys: Option<Vec<StorageKey>>,
+	/// The top level key of the most recent write, if tracking is enabled.
+	last_write_key: Option<StorageKey>,
+	/// True if the top level key of the most recent write must be tracked.
+	track_last_write: bool,
+	/// Number of times prospective changes were committed.
+	commits: u64,
+	/// Number of times prospective changes were discarded.
+	rollbacks: u64,
+	/// Summary of the prospective changes last committed or discarded.
+	last_transaction_summary: Option<TransactionSummary>,
+	/// Called whenever prospective changes are committed or discarded, if set.
+	transaction_observer: Option<TransactionObserver>,
+	/// The reason given to the last [`abort_transaction`](#method.abort_transaction).
+	#[cfg(feature = "profiling")]
+	last_abort_reason: Option<&'static str>,
+	/// Record of all writes, if enabled.
+	#[cfg(feature = "audit")]
+	audit_log: Option<Vec<AuditEntry>>,
+}
+
+/// Record of a single write, see `OverlayedChanges::enable_audit_log`.
+#[cfg(feature = "audit")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+	/// The written key.
+	pub key: StorageKey,
+	/// The storage key of the child trie holding `key`. `None` for a top level write.
+	pub child_storage_key: Option<StorageKey>,
+	/// The value visible before the write. `None` if the overlay held no value.
+	pub old_value: Option<StorageValue>,
+	/// The written value. `None` if the value was deleted.
+	pub new_value: Option<StorageValue>,
+	/// The index of the extrinsic that made the write, if collected.
+	pub extrinsic: Option<u32>,
 }
 
 /// The storage value, used inside OverlayedChanges.
@@ -80,6 +126,149 @@ pub struct OverlayedChangeSet {
 	top: BTreeMap<StorageKey, OverlayedValue>,
 	/// Child storage changes. The map key is the child storage key without the common prefix.
 	children_default: HashMap<StorageKey, (BTreeMap<StorageKey, OverlayedValue>, ChildInfo)>,
+	/// Number of writes allowed per transaction, if limited.
+	write_budget: Option<usize>,
+	/// Number of bytes allowed to be written per transaction, if limited.
+	byte_budget: Option<usize>,
+	/// Whether [`set_checked`](#method.set_checked) refuses to overwrite a change.
+	write_once: bool,
+	/// Whether [`try_set`](#method.try_set) refuses to set a value for a new key.
+	sealed: bool,
+	/// Number of top level writes per extrinsic index.
+	extrinsic_write_counts: BTreeMap<u32, usize>,
+	/// The extrinsic index of the most recent top level write tagged with one.
+	last_extrinsic_index: Option<u32>,
+	/// Number of top level writes since the change set was last cleared.
+	top_level_writes: u64,
+	/// Number of top level writes that added a key to the change set since it was last cleared.
+	written_keys: u64,
+	/// Number of top level reads and writes per key, if tracking is enabled.
+	#[cfg(feature = "profiling")]
+	access_counts: Option<RefCell<HashMap<StorageKey, (u64, u64)>>>,
+}
+
+/// A view into a single top level entry of an [`OverlayedChangeSet`], which may either be
+/// vacant or occupied.
+pub enum OverlayedEntry<'a> {
+	/// The change set holds a change for the key.
+	Occupied(OccupiedEntry<'a>),
+	/// The change set holds no change for the key.
+	Vacant(VacantEntry<'a>),
+}
+
+/// An occupied entry of an [`OverlayedChangeSet`].
+pub struct OccupiedEntry<'a> {
+	entry: btree_map::OccupiedEntry<'a, StorageKey, OverlayedValue>,
+	top_level_writes: &'a mut u64,
+}
+
+/// A vacant entry of an [`OverlayedChangeSet`].
+pub struct VacantEntry<'a> {
+	entry: btree_map::VacantEntry<'a, StorageKey, OverlayedValue>,
+	top_level_writes: &'a mut u64,
+	written_keys: &'a mut u64,
+}
+
+/// Read-only view of the top level committed changes of an [`OverlayedChanges`] that are not
+/// overridden by a prospective change.
+pub struct CommittedView<'a> {
+	committed: &'a OverlayedChangeSet,
+	prospective: &'a OverlayedChangeSet,
+}
+
+/// Read-only view of the top level prospective changes of an [`OverlayedChanges`], along with
+/// the committed value each of them overrides.
+pub struct UncommittedView<'a> {
+	prospective: &'a OverlayedChangeSet,
+	committed: &'a OverlayedChangeSet,
+}
+
+/// Writer of top level changes tagged with a fixed extrinsic index, see
+/// `OverlayedChangeSet::with_extrinsic_index`.
+pub struct ExtrinsicScope<'a> {
+	change_set: &'a mut OverlayedChangeSet,
+	index: u32,
+}
+
+/// Remaining amount of writes or bytes allowed in a transaction, see
+/// `OverlayedChangeSet::transaction_write_budget_guard` and
+/// `OverlayedChangeSet::byte_budget_guard`.
+#[derive(Debug, Clone)]
+pub struct TransactionBudget {
+	remaining: Option<usize>,
+}
+
+/// A key was written twice in write-once mode, see `OverlayedChangeSet::set_checked`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DoubleWriteError {
+	/// The key that was already written.
+	pub key: StorageKey,
+}
+
+/// A value was set for a new key in a sealed change set, see `OverlayedChangeSet::try_set`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealedChangeSetError {
+	/// The key that has no change yet.
+	pub key: StorageKey,
+}
+
+/// The budget of a transaction has been exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BudgetExhausted;
+
+/// Event reported to a transaction observer, see
+/// `OverlayedChanges::with_transaction_observer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionEvent {
+	/// Prospective changes were committed.
+	Committed {
+		/// Number of committed changes, both top level and to child storage.
+		keys_merged: usize,
+	},
+	/// Prospective changes were discarded.
+	RolledBack {
+		/// Number of discarded changes, both top level and to child storage.
+		keys_discarded: usize,
+	},
+}
+
+/// Shareable transaction observer, so that the overlay can still be cloned.
+#[derive(Clone)]
+struct TransactionObserver(Arc<dyn Fn(TransactionEvent) + Send + Sync>);
+
+impl fmt::Debug for TransactionObserver {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "TransactionObserver")
+	}
+}
+
+/// Summary of the prospective changes last committed or discarded, see
+/// `OverlayedChanges::transaction_summary`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionSummary {
+	/// Number of committed changes, both top level and to child storage.
+	pub keys_committed: usize,
+	/// Number of discarded changes, both top level and to child storage.
+	pub keys_rolled_back: usize,
+	/// Total size of the values of the changes, both top level and in child storage.
+	pub bytes_written: usize,
+	/// The extrinsic indices of the top level changes, in ascending order.
+	///
+	/// Empty if extrinsic indices are not collected.
+	pub extrinsic_indices: Vec<u32>,
+}
+
+/// The committed changes overridden by a commit, to undo it.
+///
+/// `None` means that the committed change set held no change for the key, respectively no
+/// changes for the child trie.
+struct CommitUndo {
+	top: Vec<(StorageKey, Option<OverlayedValue>)>,
+	children: Vec<(StorageKey, Option<Vec<(StorageKey, Option<OverlayedValue>)>>)>,
+	extrinsic_write_counts: BTreeMap<u32, usize>,
+	last_extrinsic_index: Option<u32>,
+	top_level_writes: u64,
+	written_keys: u64,
 }
 
 /// A storage changes structure that can be generated by the data collected in [`OverlayedChanges`].
@@ -179,11 +368,48 @@ impl std::iter::FromIterator<(StorageKey, OverlayedValue)> for OverlayedChangeSe
 	fn from_iter<T: IntoIterator<Item = (StorageKey, OverlayedValue)>>(iter: T) -> Self {
 		Self {
 			top: iter.into_iter().collect(),
-			children_default: Default::default(),
+			..Default::default()
 		}
 	}
 }
 
+#[cfg(test)]
+impl OverlayedChangeSet {
+	/// Assert that the top level value for the given key is `expected`.
+	///
+	/// An `expected` value of `None` matches both a deleted value and a key without change.
+	fn assert_value(&self, key: &[u8], expected: Option<&[u8]>) {
+		assert_eq!(
+			self.value_at(key).map(|v| &v[..]),
+			expected,
+			"Unexpected value for key {:?}",
+			key,
+		);
+	}
+}
+
+/// Encodes the top level changes as a `Vec<(StorageKey, Option<StorageValue>)>` sorted by key.
+///
+/// Child storage changes and extrinsic indices are not encoded.
+impl Encode for OverlayedChangeSet {
+	fn encode_to<T: codec::Output>(&self, dest: &mut T) {
+		let changes: Vec<_> = self.top.iter().map(|(k, v)| (k, &v.value)).collect();
+		changes.encode_to(dest)
+	}
+}
+
+impl Decode for OverlayedChangeSet {
+	fn decode<I: codec::Input>(input: &mut I) -> Result<Self, codec::Error> {
+		let changes = Vec::<(StorageKey, Option<StorageValue>)>::decode(input)?;
+		Ok(OverlayedChangeSet {
+			top: changes.into_iter()
+				.map(|(k, value)| (k, OverlayedValue { value, extrinsics: None }))
+				.collect(),
+			..Default::default()
+		})
+	}
+}
+
 impl OverlayedValue {
 	/// The most recent value contained in this overlay.
 	pub fn value(&self) -> Option<&StorageValue> {
@@ -196,6 +422,124 @@ impl OverlayedValue {
 	}
 }
 
+impl<'a> OccupiedEntry<'a> {
+	/// The key of this entry.
+	pub fn key(&self) -> &StorageKey {
+		self.entry.key()
+	}
+
+	/// The value of this entry. `None` if the value has been deleted.
+	pub fn get(&self) -> Option<&StorageValue> {
+		self.entry.get().value()
+	}
+
+	/// Set the value of this entry and return the previous one.
+	///
+	/// `None` can be used to delete the value. Extrinsic indices are kept as is.
+	pub fn insert(&mut self, value: Option<StorageValue>) -> Option<StorageValue> {
+		*self.top_level_writes += 1;
+		mem::replace(&mut self.entry.get_mut().value, value)
+	}
+
+	/// Remove the change from the change set and return its value.
+	///
+	/// Unlike inserting `None`, this does not mark the value as deleted. The change is dropped
+	/// and queries fall back to the underlying state.
+	pub fn remove(self) -> Option<StorageValue> {
+		self.entry.remove().value
+	}
+}
+
+impl<'a> VacantEntry<'a> {
+	/// The key of this entry.
+	pub fn key(&self) -> &StorageKey {
+		self.entry.key()
+	}
+
+	/// Insert a change with the given value. `None` marks the value as deleted.
+	pub fn insert(self, value: Option<StorageValue>) -> &'a OverlayedValue {
+		*self.top_level_writes += 1;
+		*self.written_keys += 1;
+		self.entry.insert(OverlayedValue { value, extrinsics: None })
+	}
+}
+
+impl<'a> CommittedView<'a> {
+	/// Get the committed value for the given key.
+	///
+	/// Returns `None` if the key has a prospective change.
+	pub fn get(&self, key: &[u8]) -> Option<&'a OverlayedValue> {
+		if self.prospective.top.contains_key(key) {
+			return None;
+		}
+		self.committed.top.get(key)
+	}
+
+	/// Iterate over all committed changes in ascending key order.
+	pub fn changes(&self) -> impl Iterator<Item=(&'a StorageKey, &'a OverlayedValue)> {
+		let prospective = &self.prospective.top;
+		self.committed.top.iter().filter(move |(k, _)| !prospective.contains_key(*k))
+	}
+
+	/// Returns the next (in lexicographic order) committed key alongside its value.
+	pub fn next_change(&self, key: &[u8]) -> Option<(&'a [u8], &'a OverlayedValue)> {
+		let range = (ops::Bound::Excluded(key), ops::Bound::Unbounded);
+		self.committed.top.range::<[u8], _>(range)
+			.find(|(k, _)| !self.prospective.top.contains_key(*k))
+			.map(|(k, v)| (&k[..], v))
+	}
+}
+
+impl<'a> UncommittedView<'a> {
+	/// Get the prospective value for the given key.
+	pub fn get(&self, key: &[u8]) -> Option<&'a OverlayedValue> {
+		self.prospective.top.get(key)
+	}
+
+	/// Get the committed value that the prospective change for the given key overrides.
+	///
+	/// Returns `None` if there is no prospective change for the key or if it overrides no
+	/// committed change.
+	pub fn base(&self, key: &[u8]) -> Option<&'a OverlayedValue> {
+		self.prospective.top.get(key).and_then(|_| self.committed.top.get(key))
+	}
+
+	/// Iterate over all prospective changes in ascending key order, along with the committed
+	/// value they override.
+	pub fn changes(&self)
+		-> impl Iterator<Item=(&'a StorageKey, &'a OverlayedValue, Option<&'a OverlayedValue>)>
+	{
+		let committed = self.committed;
+		self.prospective.top.iter().map(move |(k, v)| (k, v, committed.top.get(k)))
+	}
+}
+
+impl<'a> ExtrinsicScope<'a> {
+	/// Set the top level value for the given key, tagged with the extrinsic index of this
+	/// scope. See `OverlayedChangeSet::set`.
+	pub fn set(&mut self, key: StorageKey, value: Option<StorageValue>) {
+		self.change_set.set(key, value, Some(self.index));
+	}
+}
+
+impl TransactionBudget {
+	/// Consume `amount` from the budget.
+	///
+	/// Returns `Err(BudgetExhausted)` and leaves the budget untouched if less than `amount`
+	/// remains.
+	pub fn consume(&mut self, amount: usize) -> Result<(), BudgetExhausted> {
+		if let Some(remaining) = self.remaining.as_mut() {
+			*remaining = remaining.checked_sub(amount).ok_or(BudgetExhausted)?;
+		}
+		Ok(())
+	}
+
+	/// Returns the remaining amount, or `None` if the budget is unlimited.
+	pub fn remaining(&self) -> Option<usize> {
+		self.remaining
+	}
+}
+
 impl OverlayedChangeSet {
 	/// Whether the change set is empty.
 	pub fn is_empty(&self) -> bool {
@@ -206,723 +550,3684 @@ impl OverlayedChangeSet {
 	pub fn clear(&mut self) {
 		self.top.clear();
 		self.children_default.clear();
+		self.extrinsic_write_counts.clear();
+		self.last_extrinsic_index = None;
+		self.top_level_writes = 0;
+		self.written_keys = 0;
 	}
-}
 
-impl OverlayedChanges {
-	/// Whether the overlayed changes are empty.
-	pub fn is_empty(&self) -> bool {
-		self.prospective.is_empty() && self.committed.is_empty()
+	/// Clear the change set and reset all its statistics, keeping the allocated memory.
+	///
+	/// Settings like budgets, write-once mode and access tracking are kept.
+	pub fn clear_all_and_reset(&mut self) {
+		self.clear();
+		#[cfg(feature = "profiling")]
+		{
+			if let Some(access_counts) = self.access_counts.as_mut() {
+				access_counts.get_mut().clear();
+			}
+		}
 	}
 
-	/// Ask to collect/not to collect extrinsics indices where key(s) has been changed.
-	pub fn set_collect_extrinsics(&mut self, collect_extrinsics: bool) {
-		self.collect_extrinsics = collect_extrinsics;
+	/// Returns two independent copies of this change set.
+	///
+	/// Modifying one of them affects neither the other nor `self`.
+	pub fn fork(&self) -> (OverlayedChangeSet, OverlayedChangeSet) {
+		(self.clone(), self.clone())
 	}
 
-	/// Returns a double-Option: None if the key is unknown (i.e. and the query should be referred
-	/// to the backend); Some(None) if the key has been deleted. Some(Some(...)) for a key whose
-	/// value has been set.
-	pub fn storage(&self, key: &[u8]) -> Option<Option<&[u8]>> {
-		self.prospective.top.get(key)
-			.or_else(|| self.committed.top.get(key))
-			.map(|x| {
-				let size_read = x.value.as_ref().map(|x| x.len() as u64).unwrap_or(0);
-				self.stats.tally_read_modified(size_read);
-				x.value.as_ref().map(AsRef::as_ref)
-			})
+	/// Get the overlayed top level value for the given key.
+	///
+	/// This is the lower-level accessor for callers that need the [`OverlayedValue`]
+	/// metadata (e.g. the extrinsic indices). Use [`value_at`](#method.value_at) to only
+	/// get the value.
+	pub fn get(&self, key: &[u8]) -> Option<&OverlayedValue> {
+		#[cfg(feature = "profiling")]
+		{
+			if let Some(access_counts) = self.access_counts.as_ref() {
+				access_counts.borrow_mut().entry(key.to_vec()).or_default().0 += 1;
+			}
+		}
+		self.top.get(key)
 	}
 
-	/// Returns mutable reference to current changed value (prospective).
-	/// If there is no value in the overlay, the default callback is used to initiate
-	/// the value.
-	/// Warning this function register a change, so the mutable reference MUST be modified.
-	#[must_use = "A change was registered, so this value MUST be modified."]
-	pub fn value_mut_or_insert_with(
-		&mut self,
-		key: &[u8],
-		init: impl Fn() -> StorageValue,
-	) -> &mut StorageValue {
-		let extrinsic_index = self.extrinsic_index();
-		let committed = &self.committed.top;
+	/// Get the top level value for the given key.
+	///
+	/// Returns `None` if the key is unknown to this change set or if it has been deleted.
+	pub fn value_at(&self, key: &[u8]) -> Option<&StorageValue> {
+		self.get(key).and_then(|v| v.value())
+	}
 
-		let mut entry = self.prospective.top.entry(key.to_vec())
-			.or_insert_with(|| {
-				if let Some(overlay_state) = committed.get(key).cloned() {
-					overlay_state
-				} else {
-					OverlayedValue { value: Some(init()), ..Default::default() }
-				}
-			});
+	/// Remove all top level changes with a key in `[start, end)` and return them as a new
+	/// change set.
+	///
+	/// Child storage changes are left untouched.
+	pub fn extract_range(&mut self, start: &[u8], end: &[u8]) -> OverlayedChangeSet {
+		let mut extracted = self.top.split_off(start);
+		let mut remaining = extracted.split_off(end);
+		self.top.append(&mut remaining);
 
-		//if was deleted initialise back with empty vec
-		if entry.value.is_none() {
-			entry.value = Some(Default::default());
+		OverlayedChangeSet {
+			top: extracted,
+			..Default::default()
 		}
-		if let Some(extrinsic) = extrinsic_index {
+	}
+
+	/// Set the top level value for the given key.
+	///
+	/// `None` marks the value as deleted. If `at_extrinsic` is given, it is added to the
+	/// extrinsic indices of the value.
+	pub fn set(&mut self, key: StorageKey, value: Option<StorageValue>, at_extrinsic: Option<u32>) {
+		#[cfg(feature = "profiling")]
+		{
+			if let Some(access_counts) = self.access_counts.as_mut() {
+				access_counts.get_mut().entry(key.clone()).or_default().1 += 1;
+			}
+		}
+		let written_keys = &mut self.written_keys;
+		let entry = self.top.entry(key).or_insert_with(|| {
+			*written_keys += 1;
+			Default::default()
+		});
+		entry.value = value;
+
+		if let Some(extrinsic) = at_extrinsic {
 			entry.extrinsics.get_or_insert_with(Default::default)
 				.insert(extrinsic);
 		}
-		entry.value.as_mut().expect("Initialized above; qed")
+		self.count_writes(1, at_extrinsic);
 	}
 
-	/// Returns a double-Option: None if the key is unknown (i.e. and the query should be referred
-	/// to the backend); Some(None) if the key has been deleted. Some(Some(...)) for a key whose
-	/// value has been set.
-	pub fn child_storage(&self, child_info: &ChildInfo, key: &[u8]) -> Option<Option<&[u8]>> {
-		if let Some(map) = self.prospective.children_default.get(child_info.storage_key()) {
-			if let Some(val) = map.0.get(key) {
-				let size_read = val.value.as_ref().map(|x| x.len() as u64).unwrap_or(0);
-				self.stats.tally_read_modified(size_read);
-				return Some(val.value.as_ref().map(AsRef::as_ref));
-			}
+	/// Account for `writes` top level writes tagged with `at_extrinsic` in the statistics of
+	/// this change set.
+	fn count_writes(&mut self, writes: usize, at_extrinsic: Option<u32>) {
+		if writes == 0 {
+			return;
+		}
+		self.top_level_writes += writes as u64;
+		if let Some(extrinsic) = at_extrinsic {
+			*self.extrinsic_write_counts.entry(extrinsic).or_default() += writes;
+			self.last_extrinsic_index = Some(extrinsic);
 		}
+	}
 
-		if let Some(map) = self.committed.children_default.get(child_info.storage_key()) {
-			if let Some(val) = map.0.get(key) {
-				let size_read = val.value.as_ref().map(|x| x.len() as u64).unwrap_or(0);
-				self.stats.tally_read_modified(size_read);
-				return Some(val.value.as_ref().map(AsRef::as_ref));
+	/// Returns a writer that tags every change with the given extrinsic index.
+	pub fn with_extrinsic_index(&mut self, index: u32) -> ExtrinsicScope {
+		ExtrinsicScope { change_set: self, index }
+	}
+
+	/// Set the top level value for the given key, if its current value is `expected`.
+	///
+	/// An `expected` value of `None` matches both a deleted value and a key without change.
+	/// Returns `true` if the value was set. See [`set`](#method.set).
+	pub fn compare_and_swap(
+		&mut self,
+		key: StorageKey,
+		expected: Option<&[u8]>,
+		new: Option<StorageValue>,
+		at_extrinsic: Option<u32>,
+	) -> bool {
+		if self.value_at(&key).map(|v| &v[..]) != expected {
+			return false;
+		}
+		self.set(key, new, at_extrinsic);
+		true
+	}
+
+	/// Exchange the top level values of the two given keys, tagging both writes with
+	/// `at_extrinsic`.
+	///
+	/// A key without change is treated as deleted, so its counterpart ends up deleted. See
+	/// [`set`](#method.set).
+	pub fn swap_values(&mut self, key_a: StorageKey, key_b: StorageKey, at_extrinsic: Option<u32>) {
+		let value_a = self.top.get(&key_a).and_then(|v| v.value.clone());
+		let value_b = self.top.get(&key_b).and_then(|v| v.value.clone());
+		self.set(key_a, value_b, at_extrinsic);
+		self.set(key_b, value_a, at_extrinsic);
+	}
+
+	/// Write all changes of `source` into this change set, tagged with `extrinsic_index`.
+	///
+	/// Both top level and child storage changes are merged. The extrinsic indices recorded in
+	/// `source` are not carried over.
+	pub fn merge_extrinsic_data(&mut self, extrinsic_index: u32, source: &OverlayedChangeSet) {
+		for (key, value) in source.top.iter() {
+			self.set(key.clone(), value.value.clone(), Some(extrinsic_index));
+		}
+		for (storage_key, (map, child_info)) in source.children_default.iter() {
+			let (child_map, _) = self.children_default.entry(storage_key.clone())
+				.or_insert_with(|| (Default::default(), child_info.clone()));
+			for (key, value) in map.iter() {
+				let entry = child_map.entry(key.clone()).or_default();
+				entry.value = value.value.clone();
+				entry.extrinsics.get_or_insert_with(Default::default).insert(extrinsic_index);
 			}
 		}
+	}
 
-		None
+	/// Start counting the top level reads done through [`get`](#method.get) and the top level
+	/// writes done through [`set`](#method.set), per key.
+	///
+	/// Reads of an [`OverlayedChanges`] are counted by each change set they query: the
+	/// prospective one first, then the committed one if the key has no prospective change.
+	/// Committing prospective changes does not move the counts.
+	///
+	/// Does nothing if tracking is already enabled.
+	#[cfg(feature = "profiling")]
+	pub fn enable_access_tracking(&mut self) {
+		self.access_counts.get_or_insert_with(Default::default);
 	}
 
-	/// Inserts the given key-value pair into the prospective change set.
+	/// Returns the number of reads and writes of `key` since tracking was enabled.
 	///
-	/// `None` can be used to delete a value specified by the given key.
-	pub(crate) fn set_storage(&mut self, key: StorageKey, val: Option<StorageValue>) {
-		let size_write = val.as_ref().map(|x| x.len() as u64).unwrap_or(0);
-		self.stats.tally_write_overlay(size_write);
-		let extrinsic_index = self.extrinsic_index();
-		let entry = self.prospective.top.entry(key).or_default();
-		entry.value = val;
+	/// Returns `None` if tracking is not enabled or the key was not accessed.
+	#[cfg(feature = "profiling")]
+	pub fn access_frequency(&self, key: &[u8]) -> Option<(u64, u64)> {
+		self.access_counts.as_ref().and_then(|counts| counts.borrow().get(key).copied())
+	}
 
-		if let Some(extrinsic) = extrinsic_index {
-			entry.extrinsics.get_or_insert_with(Default::default)
-				.insert(extrinsic);
-		}
+	/// Returns up to `n` keys with the most reads and writes combined, in descending order of
+	/// that count.
+	///
+	/// Keys with the same count are ordered by ascending key. Returns an empty list if tracking
+	/// is not enabled.
+	#[cfg(feature = "profiling")]
+	pub fn top_accessed_keys(&self, n: usize) -> Vec<(StorageKey, u64)> {
+		let mut keys = self.access_counts.as_ref()
+			.map(|counts| counts.borrow().iter()
+				.map(|(k, (reads, writes))| (k.clone(), reads + writes))
+				.collect::<Vec<_>>()
+			)
+			.unwrap_or_default();
+		keys.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+		keys.truncate(n);
+		keys
 	}
 
-	/// Inserts the given key-value pair into the prospective child change set.
+	/// Make [`set_checked`](#method.set_checked) refuse to overwrite a change.
+	pub fn enable_write_once_mode(&mut self) {
+		self.write_once = true;
+	}
+
+	/// Set the top level value for the given key, like [`set`](#method.set).
 	///
-	/// `None` can be used to delete a value specified by the given key.
-	pub(crate) fn set_child_storage(
+	/// In write-once mode, returns `Err(DoubleWriteError)` and leaves the change set untouched
+	/// if the key already has a change. Otherwise, the value is always set.
+	pub fn set_checked(
 		&mut self,
-		child_info: &ChildInfo,
 		key: StorageKey,
-		val: Option<StorageValue>,
-	) {
-		let size_write = val.as_ref().map(|x| x.len() as u64).unwrap_or(0);
-		self.stats.tally_write_overlay(size_write);
-		let extrinsic_index = self.extrinsic_index();
-		let storage_key = child_info.storage_key().to_vec();
-		let map_entry = self.prospective.children_default.entry(storage_key)
-			.or_insert_with(|| (Default::default(), child_info.to_owned()));
-		let updatable = map_entry.1.try_update(child_info);
-		debug_assert!(updatable);
+		value: Option<StorageValue>,
+		at_extrinsic: Option<u32>,
+	) -> Result<(), DoubleWriteError> {
+		if self.write_once && self.top.contains_key(&key) {
+			return Err(DoubleWriteError { key });
+		}
+		self.set(key, value, at_extrinsic);
+		Ok(())
+	}
 
-		let entry = map_entry.0.entry(key).or_default();
-		entry.value = val;
+	/// Make [`try_set`](#method.try_set) refuse to set a value for a key without change.
+	pub fn seal(&mut self) {
+		self.sealed = true;
+	}
 
-		if let Some(extrinsic) = extrinsic_index {
-			entry.extrinsics.get_or_insert_with(Default::default)
-				.insert(extrinsic);
+	/// Make [`try_set`](#method.try_set) accept any write again.
+	pub fn unseal(&mut self) {
+		self.sealed = false;
+	}
+
+	/// Set the top level value for the given key, like [`set`](#method.set).
+	///
+	/// When sealed, returns `Err(SealedChangeSetError)` and leaves the change set untouched if
+	/// a value is set for a key without change. Updating a changed key and deleting are always
+	/// allowed.
+	pub fn try_set(
+		&mut self,
+		key: StorageKey,
+		value: Option<StorageValue>,
+		at_extrinsic: Option<u32>,
+	) -> Result<(), SealedChangeSetError> {
+		if self.sealed && value.is_some() && !self.top.contains_key(&key) {
+			return Err(SealedChangeSetError { key });
 		}
+		self.set(key, value, at_extrinsic);
+		Ok(())
 	}
 
-	/// Clear child storage of given storage key.
+	/// Set all values of the given genesis storage, both top level and child storage.
 	///
-	/// NOTE that this doesn't take place immediately but written into the prospective
-	/// change set, and still can be reverted by [`discard_prospective`].
+	/// The values are not tagged with any extrinsic index.
+	pub fn apply_genesis(&mut self, genesis: Storage) {
+		for (key, value) in genesis.top {
+			self.set(key, Some(value), None);
+		}
+		for (_, child) in genesis.children_default {
+			let (map, _) = self.children_default.entry(child.child_info.storage_key().to_vec())
+				.or_insert_with(|| (Default::default(), child.child_info.clone()));
+			for (key, value) in child.data {
+				map.entry(key).or_default().value = Some(value);
+			}
+		}
+	}
+
+	/// Returns the values of this change set as genesis storage.
 	///
-	/// [`discard_prospective`]: #method.discard_prospective
-	pub(crate) fn clear_child_storage(
+	/// Deleted values are left out, both top level and in child storage. Child tries without
+	/// any value are left out as well. See [`apply_genesis`](#method.apply_genesis).
+	pub fn export_as_genesis_storage(&self) -> Storage {
+		let set_values = |map: &BTreeMap<StorageKey, OverlayedValue>| map.iter()
+			.filter_map(|(k, v)| v.value.clone().map(|v| (k.clone(), v)))
+			.collect::<BTreeMap<_, _>>();
+
+		Storage {
+			top: set_values(&self.top),
+			children_default: self.children_default.iter()
+				.map(|(storage_key, (map, child_info))| (storage_key.clone(), StorageChild {
+					data: set_values(map),
+					child_info: child_info.clone(),
+				}))
+				.filter(|(_, child)| !child.data.is_empty())
+				.collect(),
+		}
+	}
+
+	/// Set all the given top level values, tagging each of them with `at_extrinsic`.
+	///
+	/// See [`set`](#method.set).
+	pub fn set_many(
 		&mut self,
-		child_info: &ChildInfo,
+		writes: impl IntoIterator<Item=(StorageKey, Option<StorageValue>)>,
+		at_extrinsic: Option<u32>,
 	) {
-		let extrinsic_index = self.extrinsic_index();
-		let storage_key = child_info.storage_key();
-		let map_entry = self.prospective.children_default.entry(storage_key.to_vec())
-			.or_insert_with(|| (Default::default(), child_info.to_owned()));
-		let updatable = map_entry.1.try_update(child_info);
-		debug_assert!(updatable);
-
-		map_entry.0.values_mut().for_each(|e| {
-			if let Some(extrinsic) = extrinsic_index {
-				e.extrinsics.get_or_insert_with(Default::default)
-					.insert(extrinsic);
-			}
+		for (key, value) in writes {
+			self.set(key, value, at_extrinsic);
+		}
+	}
 
-			e.value = None;
-		});
+	/// Apply all the given top level writes if the resulting change set satisfies `invariant`.
+	///
+	/// The writes are applied to a copy of the change set first. If `invariant` holds for the
+	/// copy, it replaces `self` and `true` is returned. Otherwise `self` is left untouched and
+	/// `false` is returned.
+	pub fn set_batch_atomic<I, F>(
+		&mut self,
+		writes: I,
+		at_extrinsic: Option<u32>,
+		invariant: F,
+	) -> bool where
+		I: IntoIterator<Item=(StorageKey, Option<StorageValue>)>,
+		F: FnOnce(&OverlayedChangeSet) -> bool,
+	{
+		let mut updated = self.clone();
+		updated.set_many(writes, at_extrinsic);
+		if invariant(&updated) {
+			*self = updated;
+			true
+		} else {
+			false
+		}
+	}
 
-		if let Some((committed_map, _child_info)) = self.committed.children_default.get(storage_key) {
-			for (key, value) in committed_map.iter() {
-				if !map_entry.0.contains_key(key) {
-					map_entry.0.insert(key.clone(), OverlayedValue {
-						value: None,
-						extrinsics: extrinsic_index.map(|i| {
-							let mut e = value.extrinsics.clone()
-								.unwrap_or_else(|| BTreeSet::default());
-							e.insert(i);
-							e
-						}),
-					});
-				}
-			}
+	/// Get the top level entry for the given key for in-place manipulation.
+	pub fn entry(&mut self, key: StorageKey) -> OverlayedEntry {
+		let top_level_writes = &mut self.top_level_writes;
+		let written_keys = &mut self.written_keys;
+		match self.top.entry(key) {
+			btree_map::Entry::Occupied(entry) =>
+				OverlayedEntry::Occupied(OccupiedEntry { entry, top_level_writes }),
+			btree_map::Entry::Vacant(entry) =>
+				OverlayedEntry::Vacant(VacantEntry { entry, top_level_writes, written_keys }),
 		}
 	}
 
-	/// Removes all key-value pairs which keys share the given prefix.
+	/// Returns the size of the largest top level value, or `0` if there is no value.
 	///
-	/// NOTE that this doesn't take place immediately but written into the prospective
-	/// change set, and still can be reverted by [`discard_prospective`].
+	/// Deleted values are ignored.
+	pub fn max_value_size(&self) -> usize {
+		self.top.values()
+			.filter_map(|v| v.value.as_ref().map(|v| v.len()))
+			.max()
+			.unwrap_or(0)
+	}
+
+	/// Returns the total size of all top level values.
 	///
-	/// [`discard_prospective`]: #method.discard_prospective
-	pub(crate) fn clear_prefix(&mut self, prefix: &[u8]) {
-		let extrinsic_index = self.extrinsic_index();
+	/// Deleted values count as `0`.
+	pub fn transaction_bytes_written(&self) -> usize {
+		self.top.values().filter_map(|v| v.value.as_ref().map(|v| v.len())).sum()
+	}
 
-		// Iterate over all prospective and mark all keys that share
-		// the given prefix as removed (None).
-		for (key, entry) in self.prospective.top.iter_mut() {
-			if key.starts_with(prefix) {
-				entry.value = None;
+	/// Returns an estimate of the encoded size of the top level changes, as they would be
+	/// committed.
+	///
+	/// Each change accounts for its key, one byte for the `Option` discriminant and its value.
+	/// Deleted values count as `0`.
+	pub fn pending_commit_size_bytes(&self) -> usize {
+		self.top.iter()
+			.map(|(k, v)| k.len() + 1 + v.value.as_ref().map_or(0, |v| v.len()))
+			.sum()
+	}
 
-				if let Some(extrinsic) = extrinsic_index {
-					entry.extrinsics.get_or_insert_with(Default::default)
-						.insert(extrinsic);
-				}
-			}
-		}
+	/// Returns the top level key holding the largest value, along with the size of that value.
+	///
+	/// Deleted values are ignored. Returns `None` if there is no value.
+	pub fn largest_value_key(&self) -> Option<(&StorageKey, usize)> {
+		self.top.iter()
+			.filter_map(|(k, v)| v.value.as_ref().map(|v| (k, v.len())))
+			.max_by_key(|(_, size)| *size)
+	}
 
-		// Then do the same with keys from committed changes.
-		// NOTE that we are making changes in the prospective change set.
-		for key in self.committed.top.keys() {
-			if key.starts_with(prefix) {
-				let entry = self.prospective.top.entry(key.clone()).or_default();
-				entry.value = None;
+	/// Returns the number of top level changes that set a value.
+	pub fn count_modified_values(&self) -> usize {
+		self.top.values().filter(|v| v.value.is_some()).count()
+	}
 
-				if let Some(extrinsic) = extrinsic_index {
-					entry.extrinsics.get_or_insert_with(Default::default)
-						.insert(extrinsic);
-				}
-			}
-		}
+	/// Returns the number of top level changes that delete a value.
+	pub fn count_deleted_values(&self) -> usize {
+		self.top.values().filter(|v| v.value.is_none()).count()
 	}
 
-	pub(crate) fn clear_child_prefix(
-		&mut self,
-		child_info: &ChildInfo,
-		prefix: &[u8],
-	) {
-		let extrinsic_index = self.extrinsic_index();
-		let storage_key = child_info.storage_key();
-		let map_entry = self.prospective.children_default.entry(storage_key.to_vec())
-			.or_insert_with(|| (Default::default(), child_info.to_owned()));
-		let updatable = map_entry.1.try_update(child_info);
-		debug_assert!(updatable);
+	/// Returns the number of distinct extrinsic indices that modified a value in this change set.
+	///
+	/// Both top level and child storage changes are taken into account. Extrinsic indices are
+	/// only collected when the runtime has announced changes trie support.
+	pub fn total_extrinsic_count(&self) -> usize {
+		self.top.values()
+			.chain(self.children_default.values().flat_map(|(map, _)| map.values()))
+			.filter_map(|v| v.extrinsics.as_ref())
+			.fold(BTreeSet::new(), |acc, extrinsics| acc.union(extrinsics).cloned().collect())
+			.len()
+	}
 
-		for (key, entry) in map_entry.0.iter_mut() {
-			if key.starts_with(prefix) {
-				entry.value = None;
+	/// Returns the union of the extrinsic indices of all top level changes in this change set.
+	///
+	/// The set is empty if extrinsic indices are not collected.
+	pub fn transaction_extrinsic_union(&self) -> BTreeSet<u32> {
+		self.top.values()
+			.filter_map(|v| v.extrinsics.as_ref())
+			.flat_map(|extrinsics| extrinsics.iter().cloned())
+			.collect()
+	}
 
-				if let Some(extrinsic) = extrinsic_index {
-					entry.extrinsics.get_or_insert_with(Default::default)
-						.insert(extrinsic);
-				}
+	/// Iterate over the top level changes made by the extrinsic with the given index.
+	///
+	/// The iterator yields the entries in ascending key order. Nothing is yielded if extrinsic
+	/// indices are not collected.
+	pub fn iter_by_extrinsic(&self, index: u32)
+		-> impl Iterator<Item=(&StorageKey, &OverlayedValue)>
+	{
+		self.top.iter()
+			.filter(move |(_, v)| v.extrinsics.as_ref().map_or(false, |e| e.contains(&index)))
+	}
+
+	/// Returns the top level keys changed by each extrinsic, indexed by extrinsic index.
+	///
+	/// Keys are listed in ascending order. The map is empty if extrinsic indices are not
+	/// collected.
+	pub fn coalesce_extrinsic_indices(&self) -> BTreeMap<u32, Vec<&StorageKey>> {
+		let mut result = BTreeMap::<u32, Vec<&StorageKey>>::new();
+		for (key, value) in self.top.iter() {
+			for extrinsic in value.extrinsics.iter().flatten() {
+				result.entry(*extrinsic).or_default().push(key);
 			}
 		}
+		result
+	}
 
-		if let Some((child_committed, _child_info)) = self.committed.children_default.get(storage_key) {
-			// Then do the same with keys from committed changes.
-			// NOTE that we are making changes in the prospective change set.
-			for key in child_committed.keys() {
-				if key.starts_with(prefix) {
-					let entry = map_entry.0.entry(key.clone()).or_default();
-					entry.value = None;
+	/// Returns the number of top level keys changed by each extrinsic, indexed by extrinsic
+	/// index.
+	///
+	/// See [`coalesce_extrinsic_indices`](#method.coalesce_extrinsic_indices).
+	pub fn entry_count_by_extrinsic(&self) -> BTreeMap<u32, usize> {
+		self.coalesce_extrinsic_indices().into_iter().map(|(i, keys)| (i, keys.len())).collect()
+	}
 
-					if let Some(extrinsic) = extrinsic_index {
-						entry.extrinsics.get_or_insert_with(Default::default)
-							.insert(extrinsic);
-					}
-				}
-			}
+	/// Returns the fraction of the top level writes that overwrote a change held by the change
+	/// set, since it was last cleared.
+	///
+	/// `0.0` means that every write added a key to the change set, values close to `1.0` mean
+	/// that almost all writes overwrote an earlier one. Returns `0.0` if nothing was written.
+	///
+	/// Every way of writing a top level value is counted, and committing prospective changes
+	/// adds their writes to the committed change set. Removing changes, e.g. with
+	/// [`prune_by_predicate`](#method.prune_by_predicate), does not affect the rate.
+	pub fn transaction_key_deduplication_rate(&self) -> f64 {
+		if self.top_level_writes == 0 {
+			return 0.0;
 		}
+		let overwrites = self.top_level_writes.saturating_sub(self.written_keys);
+		overwrites as f64 / self.top_level_writes as f64
 	}
 
-	/// Discard prospective changes to state.
-	pub fn discard_prospective(&mut self) {
-		self.prospective.clear();
+	/// Returns the extrinsic index of the most recent top level write tagged with an extrinsic
+	/// index.
+	///
+	/// Clearing the change set resets it. Committing prospective changes moves it to the
+	/// committed change set.
+	pub fn last_extrinsic_index(&self) -> Option<u32> {
+		self.last_extrinsic_index
 	}
 
-	/// Commit prospective changes to state.
-	pub fn commit_prospective(&mut self) {
-		if self.committed.is_empty() {
-			mem::swap(&mut self.prospective, &mut self.committed);
-		} else {
-			let top_to_commit = mem::replace(&mut self.prospective.top, BTreeMap::new());
-			for (key, val) in top_to_commit.into_iter() {
-				let entry = self.committed.top.entry(key).or_default();
-				entry.value = val.value;
+	/// Returns the number of top level writes made by each extrinsic, indexed by extrinsic
+	/// index.
+	///
+	/// Unlike [`entry_count_by_extrinsic`](#method.entry_count_by_extrinsic), repeated writes
+	/// to the same key are all counted. Appending to a value counts as one write, clearing a
+	/// prefix as one write per cleared key. Child storage writes are not counted.
+	///
+	/// The counts are maintained on every write, so this does not iterate over the changes.
+	pub fn extrinsic_write_counts(&self) -> BTreeMap<u32, usize> {
+		self.extrinsic_write_counts.clone()
+	}
 
-				if let Some(prospective_extrinsics) = val.extrinsics {
-					entry.extrinsics.get_or_insert_with(Default::default)
-						.extend(prospective_extrinsics);
-				}
-			}
-			for (storage_key, (map, child_info)) in self.prospective.children_default.drain() {
-				let child_content = self.committed.children_default.entry(storage_key)
-					.or_insert_with(|| (Default::default(), child_info));
-				// No update to child info at this point (will be needed for deletion).
-				for (key, val) in map.into_iter() {
-					let entry = child_content.0.entry(key).or_default();
-					entry.value = val.value;
+	/// Consume the change set and return all top level changes sorted by key in ascending
+	/// lexicographic order.
+	///
+	/// A value of `None` means that it was deleted. Child storage changes are discarded.
+	pub fn into_sorted_vec(self) -> Vec<(StorageKey, Option<StorageValue>)> {
+		self.into_pairs_iter().collect()
+	}
 
-					if let Some(prospective_extrinsics) = val.extrinsics {
-						entry.extrinsics.get_or_insert_with(Default::default)
-							.extend(prospective_extrinsics);
-					}
+	/// Consume the change set and iterate over all top level changes.
+	///
+	/// The iterator yields the entries in ascending key order. A value of `None` means that it
+	/// was deleted. Child storage changes are discarded.
+	pub fn into_pairs_iter(self) -> impl Iterator<Item=(StorageKey, Option<StorageValue>)> {
+		self.top.into_iter().map(|(k, v)| (k, v.value))
+	}
+
+	/// Iterate over all top level changes as borrowed key-value pairs.
+	///
+	/// The iterator yields the entries in ascending key order. A value of `None` means that it
+	/// was deleted.
+	pub fn to_proof_recorder_input(&self) -> impl Iterator<Item=(&[u8], Option<&[u8]>)> {
+		self.top.iter().map(|(k, v)| (&k[..], v.value.as_ref().map(|v| &v[..])))
+	}
+
+	/// Returns the top level changes as pairs of the key hashed with `hasher` and the SCALE
+	/// encoded `Option` value.
+	///
+	/// The pairs are in ascending order of the unhashed keys. Deletions are encoded as `None`.
+	pub fn serialize_for_proof(
+		&self,
+		hasher: impl Fn(&[u8]) -> Vec<u8>,
+	) -> Vec<(Vec<u8>, Vec<u8>)> {
+		self.top.iter().map(|(k, v)| (hasher(k), v.value.encode())).collect()
+	}
+
+	/// Build a trie out of the top level values of this change set alone and return all its
+	/// nodes as a storage proof.
+	///
+	/// Deletions and child storage changes are ignored. The proof is checked against the root
+	/// of that trie, not against the root of any backend.
+	pub fn to_storage_proof<H: Hasher>(&self) -> StorageProof where H::Out: Ord {
+		let mut db = MemoryDB::<H>::default();
+		let mut root = Default::default();
+		{
+			let mut trie = TrieDBMut::<H>::new(&mut db, &mut root);
+			for (key, value) in self.top.iter() {
+				if let Some(value) = value.value.as_ref() {
+					trie.insert(key, value)
+						.expect("Insertion into an in-memory trie can not fail; qed");
 				}
 			}
 		}
+		StorageProof::new(db.drain().into_iter()
+			.filter(|(_, (_, rc))| *rc > 0)
+			.map(|(_, (node, _))| node)
+			.collect())
 	}
 
-	/// Consume `OverlayedChanges` and take committed set.
+	/// Returns `true` if every top level change in `other` is also present with the same value
+	/// in `self`.
 	///
-	/// Panics:
-	/// Will panic if there are any uncommitted prospective changes.
-	fn drain_committed(&mut self) -> (
-		impl Iterator<Item=(StorageKey, Option<StorageValue>)>,
-		impl Iterator<Item=(StorageKey, (impl Iterator<Item=(StorageKey, Option<StorageValue>)>, ChildInfo))>,
-	) {
-		assert!(self.prospective.is_empty());
-		(
-			std::mem::take(&mut self.committed.top)
-				.into_iter()
-				.map(|(k, v)| (k, v.value)),
-			std::mem::take(&mut self.committed.children_default)
-				.into_iter()
-				.map(|(sk, (v, ci))| (sk, (v.into_iter().map(|(k, v)| (k, v.value)), ci))),
-		)
+	/// Deletions are compared like any other value. Extrinsic indices are ignored.
+	pub fn is_superset_of(&self, other: &OverlayedChangeSet) -> bool {
+		other.top.iter().all(|(key, value)| {
+			self.top.get(key).map_or(false, |v| v.value == value.value)
+		})
 	}
 
-	/// Get an iterator over all pending and committed child tries in the overlay.
-	pub fn child_infos(&self) -> impl IntoIterator<Item=&ChildInfo> {
-		self.committed.children_default.iter()
-			.chain(self.prospective.children_default.iter())
-			.map(|(_, v)| &v.1).collect::<BTreeSet<_>>()
+	/// Returns `true` if every top level change in `self` is also present with the same value
+	/// in `other`.
+	///
+	/// See [`is_superset_of`](#method.is_superset_of).
+	pub fn is_subset_of(&self, other: &OverlayedChangeSet) -> bool {
+		other.is_superset_of(self)
 	}
 
-	/// Get an iterator over all pending and committed changes.
+	/// Returns `true` if no key has a change in both `self` and `other`.
 	///
-	/// Supplying `None` for `child_info` will only return changes that are in the top
-	/// trie. Specifying some `child_info` will return only the changes in that
-	/// child trie.
-	pub fn changes(&self, child_info: Option<&ChildInfo>)
-		-> impl Iterator<Item=(&StorageKey, &OverlayedValue)>
-	{
-		let (committed, prospective) = if let Some(child_info) = child_info {
-			match child_info.child_type() {
-				ChildType::ParentKeyId => (
-					self.committed.children_default.get(child_info.storage_key()).map(|c| &c.0),
-					self.prospective.children_default.get(child_info.storage_key()).map(|c| &c.0),
-				),
-			}
-		} else {
-			(Some(&self.committed.top), Some(&self.prospective.top))
-		};
-		committed.into_iter().flatten().chain(prospective.into_iter().flatten())
+	/// Both top level and child storage changes are taken into account.
+	pub fn is_conflict_free_with(&self, other: &OverlayedChangeSet) -> bool {
+		let top_conflict = self.top.keys().any(|key| other.top.contains_key(key));
+		let child_conflict = self.children_default.iter().any(|(storage_key, (map, _))| {
+			other.children_default.get(storage_key)
+				.map_or(false, |(other_map, _)| map.keys().any(|key| other_map.contains_key(key)))
+		});
+		!top_conflict && !child_conflict
 	}
 
-	/// Return a clone of the currently pending changes.
-	pub fn clone_pending(&self) -> OverlayedChangeSet {
-		self.prospective.clone()
+	/// Returns the top level keys that have a change in `a` or in `b`.
+	pub fn write_set_union<'a>(
+		a: &'a OverlayedChangeSet,
+		b: &'a OverlayedChangeSet,
+	) -> HashSet<&'a StorageKey> {
+		a.top.keys().chain(b.top.keys()).collect()
 	}
 
-	/// Replace the currently pending changes.
-	pub fn replace_pending(&mut self, pending: OverlayedChangeSet) {
-		self.prospective = pending;
+	/// Iterate over the top level keys of `self` that have no change in `other`.
+	///
+	/// Keys are yielded in ascending order. Deletions count as changes in both change sets.
+	pub fn keys_not_in<'a>(
+		&'a self,
+		other: &'a OverlayedChangeSet,
+	) -> impl Iterator<Item=&'a StorageKey> {
+		self.top.keys().filter(move |key| !other.top.contains_key(*key))
 	}
 
-	/// Convert this instance with all changes into a [`StorageChanges`] instance.
-	pub fn into_storage_changes<
-		B: Backend<H>, H: Hasher, N: BlockNumber
-	>(
-		mut self,
-		backend: &B,
-		changes_trie_state: Option<&ChangesTrieState<H, N>>,
-		parent_hash: H::Out,
-		mut cache: StorageTransactionCache<B::Transaction, H, N>,
-	) -> Result<StorageChanges<B::Transaction, H, N>, String> where H::Out: Ord + Encode + 'static {
-		self.drain_storage_changes(backend, changes_trie_state, parent_hash, &mut cache)
+	/// Returns the top level changes needed to turn `base` into `self`.
+	///
+	/// This contains every change of `self` that is missing from `base` or has a different
+	/// value there, and a `None` tombstone for every change of `base` that is missing from
+	/// `self`. Entries are sorted by key. Extrinsic indices are ignored.
+	pub fn export_diff(
+		&self,
+		base: &OverlayedChangeSet,
+	) -> Vec<(StorageKey, Option<StorageValue>)> {
+		let mut diff: Vec<_> = self.top.iter()
+			.filter(|(key, value)| base.top.get(*key).map_or(true, |v| v.value != value.value))
+			.map(|(key, value)| (key.clone(), value.value.clone()))
+			.chain(base.keys_not_in(self).map(|key| (key.clone(), None)))
+			.collect();
+		diff.sort_by(|a, b| a.0.cmp(&b.0));
+		diff
 	}
 
-	/// Drain all changes into a [`StorageChanges`] instance. Leave empty overlay in place.
-	pub fn drain_storage_changes<B: Backend<H>, H: Hasher, N: BlockNumber>(
-		&mut self,
-		backend: &B,
-		changes_trie_state: Option<&ChangesTrieState<H, N>>,
-		parent_hash: H::Out,
-		mut cache: &mut StorageTransactionCache<B::Transaction, H, N>,
-	) -> Result<StorageChanges<B::Transaction, H, N>, String> where H::Out: Ord + Encode + 'static {
-		// If the transaction does not exist, we generate it.
-		if cache.transaction.is_none() {
-			self.storage_root(backend, &mut cache);
-		}
-
-		let (transaction, transaction_storage_root) = cache.transaction.take()
-			.and_then(|t| cache.transaction_storage_root.take().map(|tr| (t, tr)))
-			.expect("Transaction was be generated as part of `storage_root`; qed");
+	/// Returns `true` if applying `writes` in order would leave the top level changes unchanged.
+	///
+	/// The writes are applied to a copy of the change set, `self` is never modified. Extrinsic
+	/// indices are ignored.
+	pub fn is_idempotent_replay(&self, writes: &[(StorageKey, Option<StorageValue>)]) -> bool {
+		let mut replayed = self.clone();
+		replayed.set_many(writes.iter().cloned(), None);
+		self.is_superset_of(&replayed)
+	}
 
-		// If the transaction does not exist, we generate it.
-		if cache.changes_trie_transaction.is_none() {
-			self.changes_trie_root(
-				backend,
-				changes_trie_state,
-				parent_hash,
-				false,
-				&mut cache,
-			).map_err(|_| "Failed to generate changes trie transaction")?;
+	/// Apply `f` to every top level value and store the result in place.
+	///
+	/// Keys for which `f` returns `None` are marked as deleted. Extrinsic indices are kept as is.
+	pub fn map_values<F>(&mut self, mut f: F)
+		where F: FnMut(&StorageKey, Option<StorageValue>) -> Option<StorageValue>
+	{
+		for (key, entry) in self.top.iter_mut() {
+			entry.value = f(key, entry.value.take());
 		}
+		self.top_level_writes += self.top.len() as u64;
+	}
 
-		let changes_trie_transaction = cache.changes_trie_transaction
-			.take()
-			.expect("Changes trie transaction was generated by `changes_trie_root`; qed");
+	/// Returns `n` distinct top level changes picked pseudo-randomly.
+	///
+	/// The selection is deterministic for a given `seed`, as the pseudo-random generator is
+	/// ChaCha20. If the change set holds fewer than `n` changes, all of them are returned. The
+	/// result is in ascending key order.
+	pub fn sample(&self, n: usize, seed: u64) -> Vec<(&StorageKey, &OverlayedValue)> {
+		use rand::{SeedableRng, seq::index};
+		use rand_chacha::ChaChaRng;
 
-		let offchain_storage_changes = Default::default();
-		let (main_storage_changes, child_storage_changes) = self.drain_committed();
+		let entries: Vec<_> = self.top.iter().collect();
+		let mut rng = ChaChaRng::seed_from_u64(seed);
+		let mut indices = index::sample(&mut rng, entries.len(), n.min(entries.len())).into_vec();
+		indices.sort_unstable();
+		indices.into_iter().map(|i| entries[i]).collect()
+	}
 
-		Ok(StorageChanges {
-			main_storage_changes: main_storage_changes.collect(),
-			child_storage_changes: child_storage_changes.map(|(sk, it)| (sk, it.0.collect())).collect(),
-			offchain_storage_changes,
-			transaction,
-			transaction_storage_root,
-			changes_trie_transaction,
-		})
+	/// Returns the number of top level changes per range of first key bytes.
+	///
+	/// Bucket `i` counts the keys whose first byte is in
+	/// `[i * bucket_size, (i + 1) * bucket_size)`.
+	/// Empty keys are counted in the first bucket.
+	///
+	/// # Panics
+	///
+	/// Panics if `bucket_size` is `0`.
+	pub fn key_histogram(&self, bucket_size: usize) -> Vec<usize> {
+		assert!(bucket_size > 0, "Bucket size must not be zero");
+		self.first_byte_histogram(255 / bucket_size + 1, |b| b / bucket_size)
 	}
 
-	/// Inserts storage entry responsible for current extrinsic index.
-	#[cfg(test)]
-	pub(crate) fn set_extrinsic_index(&mut self, extrinsic_index: u32) {
-		self.prospective.top.insert(EXTRINSIC_INDEX.to_vec(), OverlayedValue {
-			value: Some(extrinsic_index.encode()),
-			extrinsics: None,
-		});
+	/// Returns the number of top level changes in each of `buckets` equal ranges of first key
+	/// bytes.
+	///
+	/// Unlike [`key_histogram`](#method.key_histogram), the number of buckets is given instead
+	/// of their size. A key whose first byte is `b` is counted in bucket `b * buckets / 256`,
+	/// so with more than 256 buckets some of them are always empty. Empty keys are counted in
+	/// the first bucket.
+	///
+	/// # Panics
+	///
+	/// Panics if `buckets` is `0`.
+	pub fn write_histogram(&self, buckets: usize) -> Vec<usize> {
+		assert!(buckets > 0, "Number of buckets must not be zero");
+		self.first_byte_histogram(buckets, |b| b * buckets / 256)
 	}
 
-	/// Returns current extrinsic index to use in changes trie construction.
-	/// None is returned if it is not set or changes trie config is not set.
-	/// Persistent value (from the backend) can be ignored because runtime must
-	/// set this index before first and unset after last extrinsic is executed.
-	/// Changes that are made outside of extrinsics, are marked with
-	/// `NO_EXTRINSIC_INDEX` index.
-	fn extrinsic_index(&self) -> Option<u32> {
-		match self.collect_extrinsics {
-			true => Some(
-				self.storage(EXTRINSIC_INDEX)
-					.and_then(|idx| idx.and_then(|idx| Decode::decode(&mut &*idx).ok()))
-					.unwrap_or(NO_EXTRINSIC_INDEX)),
-			false => None,
+	/// Count the top level changes in `buckets` buckets, using `bucket_of` to map the first
+	/// byte of a key to its bucket.
+	///
+	/// Empty keys are mapped like a first byte of `0`.
+	fn first_byte_histogram(
+		&self,
+		buckets: usize,
+		bucket_of: impl Fn(usize) -> usize,
+	) -> Vec<usize> {
+		let mut counts = vec![0; buckets];
+		for key in self.top.keys() {
+			counts[bucket_of(key.first().cloned().unwrap_or(0) as usize)] += 1;
 		}
+		counts
 	}
 
-	/// Generate the storage root using `backend` and all changes from `prospective` and `committed`.
+	/// Call `f` on every top level value with a key in `[start, end)`, in ascending key order.
 	///
-	/// Returns the storage root and caches storage transaction in the given `cache`.
-	pub fn storage_root<H: Hasher, N: BlockNumber, B: Backend<H>>(
-		&self,
-		backend: &B,
-		cache: &mut StorageTransactionCache<B::Transaction, H, N>,
-	) -> H::Out
-		where H::Out: Ord + Encode,
+	/// `f` can modify the value in place. Setting it to `None` marks it as deleted. Extrinsic
+	/// indices are kept as is.
+	pub fn apply_predicate_to_range<F>(&mut self, start: &[u8], end: &[u8], mut f: F)
+		where F: FnMut(&StorageKey, &mut Option<StorageValue>)
 	{
-		let child_storage_keys = self.prospective.children_default.keys()
-				.chain(self.committed.children_default.keys());
-		let child_delta_iter = child_storage_keys.map(|storage_key|
-			(
-				self.default_child_info(storage_key)
-					.expect("child info initialized in either committed or prospective"),
-				self.committed.children_default.get(storage_key)
-					.into_iter()
-					.flat_map(|(map, _)|
-						map.iter().map(|(k, v)| (&k[..], v.value().map(|v| &v[..])))
-					)
-					.chain(
-						self.prospective.children_default.get(storage_key)
-							.into_iter()
-							.flat_map(|(map, _)|
-								map.iter().map(|(k, v)| (&k[..], v.value().map(|v| &v[..])))
-							)
-					),
-			)
+		let range = (ops::Bound::Included(start), ops::Bound::Excluded(end));
+		for (key, entry) in self.top.range_mut::<[u8], _>(range) {
+			f(key, &mut entry.value);
+			self.top_level_writes += 1;
+		}
+	}
+
+	/// Returns the number of top level changes whose key starts with `prefix`.
+	///
+	/// Deletions are counted as changes.
+	pub fn count_changes_in_prefix(&self, prefix: &[u8]) -> usize {
+		self.top.range::<[u8], _>((ops::Bound::Included(prefix), ops::Bound::Unbounded))
+			.take_while(|(key, _)| key.starts_with(prefix))
+			.count()
+	}
+
+	/// Iterate over the top level changes whose key starts with `prefix` and is greater than
+	/// `start`.
+	///
+	/// Passing the last key of a previous call as `start` resumes the iteration after it.
+	pub fn iter_prefix_from<'a>(
+		&'a self,
+		prefix: &'a [u8],
+		start: &'a [u8],
+	) -> impl Iterator<Item=(&'a StorageKey, &'a OverlayedValue)> {
+		let lower = if start >= prefix {
+			ops::Bound::Excluded(start)
+		} else {
+			ops::Bound::Included(prefix)
+		};
+		self.top.range::<[u8], _>((lower, ops::Bound::Unbounded))
+			.take_while(move |(key, _)| key.starts_with(prefix))
+	}
+
+	/// Group the top level keys by their first 16 bytes, i.e. the hashed module prefix.
+	///
+	/// Keys shorter than 16 bytes are ignored. Keys are listed in ascending order within each
+	/// group.
+	pub fn group_by_module_prefix(&self) -> HashMap<[u8; 16], Vec<&StorageKey>> {
+		let mut groups = HashMap::<[u8; 16], Vec<&StorageKey>>::new();
+		for key in self.top.keys().filter(|key| key.len() >= 16) {
+			let mut prefix = [0u8; 16];
+			prefix.copy_from_slice(&key[..16]);
+			groups.entry(prefix).or_default().push(key);
+		}
+		groups
+	}
+
+	/// Returns `true` if a top level change whose key starts with `prefix` sets a value.
+	///
+	/// Deletions are ignored.
+	pub fn has_key_in_prefix(&self, prefix: &[u8]) -> bool {
+		self.top.range::<[u8], _>((ops::Bound::Included(prefix), ops::Bound::Unbounded))
+			.take_while(|(key, _)| key.starts_with(prefix))
+			.any(|(_, value)| value.value.is_some())
+	}
+
+	/// Returns the number of top level changes per key prefix of `prefix_len` bytes.
+	///
+	/// Keys shorter than `prefix_len` are counted under the whole key.
+	pub fn key_prefix_stats(&self, prefix_len: usize) -> BTreeMap<Vec<u8>, usize> {
+		let mut stats = BTreeMap::new();
+		for key in self.top.keys() {
+			let prefix = &key[..prefix_len.min(key.len())];
+			*stats.entry(prefix.to_vec()).or_default() += 1;
+		}
+		stats
+	}
+
+	/// Remove the top level deletions of keys that are unknown to the backend.
+	///
+	/// `backend_has_key` tells whether the backend holds a value for the given key. Deleting a
+	/// key that the backend doesn't hold has no effect, so such changes can be dropped.
+	pub fn gc_tombstones<F: Fn(&StorageKey) -> bool>(&mut self, backend_has_key: F) {
+		self.prune_by_predicate(|key, value| value.value.is_none() && !backend_has_key(key));
+	}
+
+	/// Remove all top level changes for which `f` returns `true`.
+	///
+	/// Unlike deleting a value, this does not mark the value as deleted. The changes are
+	/// dropped and queries fall back to the underlying state.
+	pub fn prune_by_predicate<F: Fn(&StorageKey, &OverlayedValue) -> bool>(&mut self, f: F) {
+		let pruned: Vec<_> = self.top.iter()
+			.filter(|(key, value)| f(key, value))
+			.map(|(key, _)| key.clone())
+			.collect();
+		for key in pruned {
+			self.top.remove(&key);
+		}
+	}
+
+	/// Limit the number of writes per transaction to `budget`.
+	///
+	/// The limit is enforced by the callers through
+	/// [`transaction_write_budget_guard`](#method.transaction_write_budget_guard).
+	pub fn set_write_budget(&mut self, budget: usize) {
+		self.write_budget = Some(budget);
+	}
+
+	/// Returns a fresh write budget for a transaction.
+	///
+	/// The budget is unlimited unless [`set_write_budget`](#method.set_write_budget) was
+	/// called.
+	pub fn transaction_write_budget_guard(&self) -> TransactionBudget {
+		TransactionBudget { remaining: self.write_budget }
+	}
+
+	/// Limit the number of bytes written per transaction to `bytes`.
+	///
+	/// The limit is enforced by the callers through
+	/// [`byte_budget_guard`](#method.byte_budget_guard).
+	pub fn set_byte_budget(&mut self, bytes: usize) {
+		self.byte_budget = Some(bytes);
+	}
+
+	/// Returns a fresh byte budget for a transaction.
+	///
+	/// The budget is unlimited unless [`set_byte_budget`](#method.set_byte_budget) was called.
+	pub fn byte_budget_guard(&self) -> TransactionBudget {
+		TransactionBudget { remaining: self.byte_budget }
+	}
+
+	/// Release the memory over-allocated by the child storage changes.
+	///
+	/// The top level changes are stored in a `BTreeMap` that does not over-allocate.
+	pub fn shrink_to_fit(&mut self) {
+		self.children_default.shrink_to_fit();
+	}
+
+	/// Drop the extrinsic indices of all top level and child storage changes, along with the
+	/// [`extrinsic_write_counts`](#method.extrinsic_write_counts).
+	pub fn strip_extrinsic_data(&mut self) {
+		let values = self.top.values_mut()
+			.chain(self.children_default.values_mut().flat_map(|(map, _)| map.values_mut()));
+		for value in values {
+			value.extrinsics = None;
+		}
+		self.extrinsic_write_counts.clear();
+	}
+
+	/// Move the statistics of this change set to `committed`, as part of committing its
+	/// changes.
+	fn commit_statistics(&mut self, committed: &mut OverlayedChangeSet) {
+		for (extrinsic, count) in mem::take(&mut self.extrinsic_write_counts) {
+			*committed.extrinsic_write_counts.entry(extrinsic).or_default() += count;
+		}
+		if let Some(index) = self.last_extrinsic_index.take() {
+			committed.last_extrinsic_index = Some(index);
+		}
+		committed.top_level_writes += mem::take(&mut self.top_level_writes);
+		self.written_keys = 0;
+		committed.written_keys += self.top.keys()
+			.filter(|key| !committed.top.contains_key(*key))
+			.count() as u64;
+	}
+
+	/// Returns what is needed to undo committing the changes of `prospective` into this
+	/// change set.
+	///
+	/// Only the changes overridden by `prospective` are copied.
+	fn commit_undo(&self, prospective: &OverlayedChangeSet) -> CommitUndo {
+		fn overridden(
+			committed: &BTreeMap<StorageKey, OverlayedValue>,
+			changes: &BTreeMap<StorageKey, OverlayedValue>,
+		) -> Vec<(StorageKey, Option<OverlayedValue>)> {
+			changes.keys().map(|key| (key.clone(), committed.get(key).cloned())).collect()
+		}
+
+		CommitUndo {
+			top: overridden(&self.top, &prospective.top),
+			children: prospective.children_default.iter()
+				.map(|(storage_key, (map, _))| (
+					storage_key.clone(),
+					self.children_default.get(storage_key)
+						.map(|(committed, _)| overridden(committed, map)),
+				))
+				.collect(),
+			extrinsic_write_counts: self.extrinsic_write_counts.clone(),
+			last_extrinsic_index: self.last_extrinsic_index,
+			top_level_writes: self.top_level_writes,
+			written_keys: self.written_keys,
+		}
+	}
+
+	/// Undo a commit into this change set. See [`commit_undo`](#method.commit_undo).
+	fn undo_commit(&mut self, undo: CommitUndo) {
+		fn restore(
+			map: &mut BTreeMap<StorageKey, OverlayedValue>,
+			values: Vec<(StorageKey, Option<OverlayedValue>)>,
+		) {
+			for (key, value) in values {
+				match value {
+					Some(value) => { map.insert(key, value); },
+					None => { map.remove(&key); },
+				}
+			}
+		}
+
+		restore(&mut self.top, undo.top);
+		for (storage_key, values) in undo.children {
+			match values {
+				Some(values) => {
+					if let Some((map, _)) = self.children_default.get_mut(&storage_key) {
+						restore(map, values);
+					}
+				},
+				None => { self.children_default.remove(&storage_key); },
+			}
+		}
+		self.extrinsic_write_counts = undo.extrinsic_write_counts;
+		self.last_extrinsic_index = undo.last_extrinsic_index;
+		self.top_level_writes = undo.top_level_writes;
+		self.written_keys = undo.written_keys;
+	}
+}
+
+impl OverlayedChanges {
+	/// Whether the overlayed changes are empty.
+	pub fn is_empty(&self) -> bool {
+		self.prospective.is_empty() && self.committed.is_empty()
+	}
+
+	/// Whether there are prospective changes that are not yet committed.
+	pub fn has_uncommitted_changes(&self) -> bool {
+		!self.prospective.is_empty()
+	}
+
+	/// Ask to collect/not to collect extrinsics indices where key(s) has been changed.
+	pub fn set_collect_extrinsics(&mut self, collect_extrinsics: bool) {
+		self.collect_extrinsics = collect_extrinsics;
+	}
+
+	/// Returns a double-Option: None if the key is unknown (i.e. and the query should be referred
+	/// to the backend); Some(None) if the key has been deleted. Some(Some(...)) for a key whose
+	/// value has been set.
+	pub fn storage(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+		self.prospective.get(key)
+			.or_else(|| self.committed.get(key))
+			.map(|x| {
+				let size_read = x.value.as_ref().map(|x| x.len() as u64).unwrap_or(0);
+				self.stats.tally_read_modified(size_read);
+				x.value.as_ref().map(AsRef::as_ref)
+			})
+	}
+
+	/// Returns the number of overlay layers holding a version of the given top level key.
+	///
+	/// This counts the committed version and the prospective version, so the result is
+	/// at most `2`. A key that was never written returns `0`.
+	pub fn transaction_count_for_key(&self, key: &[u8]) -> usize {
+		self.committed.top.contains_key(key) as usize
+			+ self.prospective.top.contains_key(key) as usize
+	}
+
+	/// Returns the number of changes [`discard_prospective`] would drop.
+	///
+	/// Both top level and child storage changes are counted. The cost is linear in the number
+	/// of child tries with prospective changes, not in the number of changes.
+	///
+	/// [`discard_prospective`]: #method.discard_prospective
+	pub fn transaction_rollback_cost(&self) -> usize {
+		self.prospective.top.len()
+			+ self.prospective.children_default.values().map(|(map, _)| map.len()).sum::<usize>()
+	}
+
+	/// Returns `true` if the given top level key has a prospective change.
+	pub fn key_in_current_transaction(&self, key: &[u8]) -> bool {
+		self.prospective.top.contains_key(key)
+	}
+
+	/// Panics if the given top level key has a prospective change.
+	///
+	/// See [`key_in_current_transaction`](#method.key_in_current_transaction).
+	pub fn assert_key_not_in_transaction(&self, key: &[u8]) {
+		assert!(
+			!self.key_in_current_transaction(key),
+			"Key {} was already written in the current transaction",
+			HexDisplay::from(&key),
 		);
+	}
 
-		// compute and memoize
-		let delta = self.committed
-			.top
-			.iter()
-			.map(|(k, v)| (&k[..], v.value().map(|v| &v[..])))
-			.chain(self.prospective.top.iter().map(|(k, v)| (&k[..], v.value().map(|v| &v[..]))));
+	/// Iterate over the top level keys whose current value is deleted.
+	///
+	/// Keys deleted by a prospective change are yielded first, then the keys deleted by a
+	/// committed change that is not overridden, each in ascending order.
+	pub fn iter_deleted_keys(&self) -> impl Iterator<Item=&StorageKey> {
+		let prospective = &self.prospective.top;
+		prospective.iter()
+			.chain(self.committed.top.iter().filter(move |(k, _)| !prospective.contains_key(*k)))
+			.filter(|(_, v)| v.value.is_none())
+			.map(|(k, _)| k)
+	}
+
+	/// Returns the versions of the given top level key, from oldest to newest, along with the
+	/// layer holding each of them.
+	///
+	/// The layer is `0` for the committed version and `1` for the prospective one. Returns
+	/// `None` if the key has no change.
+	pub fn key_version_history(&self, key: &[u8]) -> Option<Vec<(Option<&StorageValue>, usize)>> {
+		let history = [self.committed.top.get(key), self.prospective.top.get(key)].iter()
+			.enumerate()
+			.filter_map(|(layer, value)| value.map(|v| (v.value.as_ref(), layer)))
+			.collect::<Vec<_>>();
+		if history.is_empty() {
+			None
+		} else {
+			Some(history)
+		}
+	}
+
+	/// Iterate over the committed top level keys whose value is larger than `min_bytes`,
+	/// along with the size of that value.
+	///
+	/// Deleted values and changes overridden by a prospective change are ignored, see
+	/// [`committed_view`](#method.committed_view).
+	pub fn committed_keys_with_size_above<'a>(
+		&'a self,
+		min_bytes: usize,
+	) -> impl Iterator<Item=(&'a StorageKey, usize)> {
+		self.committed_view().changes()
+			.filter_map(|(k, v)| v.value().map(|v| (k, v.len())))
+			.filter(move |(_, size)| *size > min_bytes)
+	}
+
+	/// Returns the extrinsic indices of all prospective top level changes.
+	///
+	/// The set is empty if extrinsic indices are not collected. See
+	/// [`OverlayedChangeSet::transaction_extrinsic_union`].
+	pub fn transaction_extrinsic_indices(&self) -> BTreeSet<u32> {
+		self.prospective.transaction_extrinsic_union()
+	}
+
+	/// Start recording all writes, both top level and to child storage.
+	///
+	/// Clearing a prefix or a child trie is recorded as one deletion per key with a change in
+	/// the overlay. Writes made directly through [`value_mut_or_insert_with`] are not recorded,
+	/// as their value is only known once the caller modified it.
+	///
+	/// The records can be retrieved with [`drain_audit_log`]. Calling this when recording is
+	/// already enabled has no effect.
+	///
+	/// [`value_mut_or_insert_with`]: #method.value_mut_or_insert_with
+	/// [`drain_audit_log`]: #method.drain_audit_log
+	#[cfg(feature = "audit")]
+	pub fn enable_audit_log(&mut self) {
+		self.audit_log.get_or_insert_with(Default::default);
+	}
+
+	/// Take all writes recorded since the last call, in the order they were made.
+	///
+	/// Writes that were discarded since are still part of the log. Returns an empty list if
+	/// recording is not enabled.
+	#[cfg(feature = "audit")]
+	pub fn drain_audit_log(&mut self) -> Vec<AuditEntry> {
+		self.audit_log.as_mut().map(mem::take).unwrap_or_default()
+	}
+
+	/// Returns the prospective and committed changes of the given child trie, or the top level
+	/// ones if `child_info` is `None`.
+	#[cfg(feature = "audit")]
+	fn audited_maps(&self, child_info: Option<&ChildInfo>) -> (
+		Option<&BTreeMap<StorageKey, OverlayedValue>>,
+		Option<&BTreeMap<StorageKey, OverlayedValue>>,
+	) {
+		match child_info {
+			Some(child_info) => (
+				self.prospective.children_default.get(child_info.storage_key()).map(|(map, _)| map),
+				self.committed.children_default.get(child_info.storage_key()).map(|(map, _)| map),
+			),
+			None => (Some(&self.prospective.top), Some(&self.committed.top)),
+		}
+	}
+
+	/// Returns the value the overlay holds for the given key, as recorded in the audit log.
+	#[cfg(feature = "audit")]
+	fn audited_value(&self, child_info: Option<&ChildInfo>, key: &[u8]) -> Option<StorageValue> {
+		let (prospective, committed) = self.audited_maps(child_info);
+		prospective.and_then(|map| map.get(key))
+			.or_else(|| committed.and_then(|map| map.get(key)))
+			.and_then(|v| v.value.clone())
+	}
+
+	/// Record a write in the audit log, if enabled.
+	///
+	/// Must be called before the write is applied, so that the previous value is recorded.
+	#[cfg(feature = "audit")]
+	fn record_write(
+		&mut self,
+		child_info: Option<&ChildInfo>,
+		key: &[u8],
+		new_value: Option<&StorageValue>,
+		extrinsic: Option<u32>,
+	) {
+		if self.audit_log.is_none() {
+			return;
+		}
+		let entry = AuditEntry {
+			key: key.to_vec(),
+			child_storage_key: child_info.map(|c| c.storage_key().to_vec()),
+			old_value: self.audited_value(child_info, key),
+			new_value: new_value.cloned(),
+			extrinsic,
+		};
+		self.audit_log.as_mut().expect("Checked above; qed").push(entry);
+	}
+
+	/// Record the deletion of every key with a change that starts with `prefix` in the audit
+	/// log, if enabled.
+	///
+	/// Must be called before the deletion is applied. See [`record_write`].
+	///
+	/// [`record_write`]: #method.record_write
+	#[cfg(feature = "audit")]
+	fn record_clear(
+		&mut self,
+		child_info: Option<&ChildInfo>,
+		prefix: &[u8],
+		extrinsic: Option<u32>,
+	) {
+		if self.audit_log.is_none() {
+			return;
+		}
+		let (prospective, committed) = self.audited_maps(child_info);
+		let keys = prospective.into_iter().chain(committed)
+			.flat_map(|map| map.keys())
+			.filter(|key| key.starts_with(prefix))
+			.cloned()
+			.collect::<BTreeSet<_>>();
+		for key in keys {
+			self.record_write(child_info, &key, None, extrinsic);
+		}
+	}
+
+	/// Returns the number of writes made to this overlay since its creation.
+	///
+	/// Both top level and child storage writes are counted. Appending to a value counts as one
+	/// write, clearing a prefix or a child trie as one write per cleared key with a change in
+	/// the overlay. Discarding changes does not decrease the count.
+	pub fn total_writes_since_creation(&self) -> u64 {
+		*self.stats.writes_overlay.borrow()
+	}
+
+	/// Tally the deletion of `cleared` keys in the statistics, as one write each.
+	fn tally_clears(&self, cleared: usize) {
+		for _ in 0..cleared {
+			self.stats.tally_write_overlay(0);
+		}
+	}
+
+	/// Returns the number of calls to [`commit_prospective`] since the creation of this overlay.
+	///
+	/// [`commit_prospective`]: #method.commit_prospective
+	pub fn transaction_committed_count(&self) -> u64 {
+		self.commits
+	}
+
+	/// Returns the number of calls to [`discard_prospective`] since the creation of this overlay.
+	///
+	/// A [`conditional_commit`] whose predicate fails is counted as discarding changes as well.
+	///
+	/// [`discard_prospective`]: #method.discard_prospective
+	/// [`conditional_commit`]: #method.conditional_commit
+	pub fn transaction_rolled_back_count(&self) -> u64 {
+		self.rollbacks
+	}
+
+	/// Start tracking the top level key of the most recent write.
+	///
+	/// See [`last_write_key`](#method.last_write_key).
+	pub fn enable_last_write_tracking(&mut self) {
+		self.track_last_write = true;
+	}
+
+	/// Returns the top level key of the most recent write, regardless of whether the write was
+	/// committed or discarded since.
+	///
+	/// Returns `None` if nothing was written since tracking was enabled with
+	/// [`enable_last_write_tracking`](#method.enable_last_write_tracking).
+	pub fn last_write_key(&self) -> Option<&StorageKey> {
+		self.last_write_key.as_ref()
+	}
+
+	/// Remember `key` as the key of the most recent write, if tracking is enabled.
+	///
+	/// The buffer of the previous key is reused.
+	fn note_last_write(&mut self, key: &[u8]) {
+		if self.track_last_write {
+			let last_write_key = self.last_write_key.get_or_insert_with(Vec::new);
+			last_write_key.clear();
+			last_write_key.extend_from_slice(key);
+		}
+	}
+
+	/// Iterate over the committed top level changes with a key greater or equal to `start`, in
+	/// ascending key order.
+	///
+	/// Changes that are overridden by a prospective change are skipped. A value of `None` means
+	/// that it was deleted.
+	pub fn committed_iter_from<'a>(
+		&'a self,
+		start: &'a [u8],
+	) -> impl Iterator<Item=(&'a StorageKey, Option<&'a StorageValue>)> {
+		let prospective = &self.prospective.top;
+		self.committed.top.range::<[u8], _>((ops::Bound::Included(start), ops::Bound::Unbounded))
+			.filter(move |(k, _)| !prospective.contains_key(*k))
+			.map(|(k, v)| (k, v.value.as_ref()))
+	}
+
+	/// Returns the number of committed top level changes.
+	pub fn committed_key_count(&self) -> usize {
+		self.committed.top.len()
+	}
+
+	/// Returns the number of prospective top level changes.
+	pub fn uncommitted_key_count(&self) -> usize {
+		self.prospective.top.len()
+	}
+
+	/// Returns the number of committed top level changes that are not overridden by a
+	/// prospective change.
+	///
+	/// Keys that only have a prospective change are not counted.
+	pub fn committed_value_count(&self) -> usize {
+		self.committed.top.len() - self.open_value_count()
+	}
+
+	/// Returns the number of top level keys with a committed version that is overridden by a
+	/// prospective one.
+	///
+	/// These are the keys for which [`transaction_count_for_key`] returns `2`.
+	///
+	/// [`transaction_count_for_key`]: #method.transaction_count_for_key
+	pub fn open_value_count(&self) -> usize {
+		self.prospective.top.keys().filter(|k| self.committed.top.contains_key(*k)).count()
+	}
+
+	/// Returns `true` if a top level key has both a committed and a prospective version.
+	///
+	/// This is the case when a key is written again after its change has been committed.
+	pub fn is_write_amplified(&self) -> bool {
+		self.open_value_count() > 0
+	}
+
+	/// Returns a view of the committed top level changes.
+	///
+	/// Changes that are overridden by a prospective change are skipped, like in
+	/// [`committed_iter_from`](#method.committed_iter_from).
+	pub fn committed_view(&self) -> CommittedView {
+		CommittedView {
+			committed: &self.committed,
+			prospective: &self.prospective,
+		}
+	}
+
+	/// Returns the committed top level changes.
+	///
+	/// Changes that are overridden by a prospective change are skipped, see
+	/// [`committed_view`](#method.committed_view). A value of `None` means that it was deleted.
+	pub fn committed_to_btreemap(&self) -> BTreeMap<&StorageKey, Option<&StorageValue>> {
+		self.committed_view().changes().map(|(k, v)| (k, v.value.as_ref())).collect()
+	}
+
+	/// Consume the overlay and return the committed top level changes.
+	///
+	/// Changes that are overridden by a prospective change are skipped, see
+	/// [`committed_view`](#method.committed_view). A value of `None` means that it was deleted.
+	pub fn committed_into_btreemap(self) -> BTreeMap<StorageKey, Option<StorageValue>> {
+		let prospective = self.prospective.top;
+		self.committed.top.into_iter()
+			.filter(|(k, _)| !prospective.contains_key(k))
+			.map(|(k, v)| (k, v.value))
+			.collect()
+	}
+
+	/// Returns a view of the prospective top level changes, that would be discarded by
+	/// [`discard_prospective`].
+	///
+	/// [`discard_prospective`]: #method.discard_prospective
+	pub fn uncommitted_view(&self) -> UncommittedView {
+		UncommittedView {
+			prospective: &self.prospective,
+			committed: &self.committed,
+		}
+	}
+
+	/// Iterate over the prospective top level changes in ascending key order.
+	///
+	/// Unlike [`uncommitted_view`](#method.uncommitted_view), the committed values they
+	/// override are not accessible.
+	pub fn uncommitted_iter(&self) -> impl Iterator<Item=(&StorageKey, &OverlayedValue)> {
+		self.prospective.top.iter()
+	}
+
+	/// Returns mutable reference to current changed value (prospective).
+	/// If there is no value in the overlay, the default callback is used to initiate
+	/// the value.
+	/// Warning this function register a change, so the mutable reference MUST be modified.
+	/// The write is tallied in the statistics without any size, as the value is only known
+	/// once the caller modified it.
+	#[must_use = "A change was registered, so this value MUST be modified."]
+	pub fn value_mut_or_insert_with(
+		&mut self,
+		key: &[u8],
+		init: impl Fn() -> StorageValue,
+	) -> &mut StorageValue {
+		self.stats.tally_write_overlay(0);
+		self.prospective_value_mut(key, init)
+	}
+
+	/// Returns a mutable reference to the prospective value of the given key, registering a
+	/// write. See [`value_mut_or_insert_with`](#method.value_mut_or_insert_with).
+	///
+	/// The write is not tallied in the statistics.
+	fn prospective_value_mut(
+		&mut self,
+		key: &[u8],
+		init: impl Fn() -> StorageValue,
+	) -> &mut StorageValue {
+		let extrinsic_index = self.extrinsic_index();
+		self.prospective.count_writes(1, extrinsic_index);
+		self.note_last_write(key);
+		let committed = &self.committed.top;
+		let written_keys = &mut self.prospective.written_keys;
+
+		let mut entry = self.prospective.top.entry(key.to_vec())
+			.or_insert_with(|| {
+				*written_keys += 1;
+				if let Some(overlay_state) = committed.get(key).cloned() {
+					overlay_state
+				} else {
+					OverlayedValue { value: Some(init()), ..Default::default() }
+				}
+			});
+
+		//if was deleted initialise back with empty vec
+		if entry.value.is_none() {
+			entry.value = Some(Default::default());
+		}
+		if let Some(extrinsic) = extrinsic_index {
+			entry.extrinsics.get_or_insert_with(Default::default)
+				.insert(extrinsic);
+		}
+		entry.value.as_mut().expect("Initialized above; qed")
+	}
+
+	/// Returns a double-Option: None if the key is unknown (i.e. and the query should be referred
+	/// to the backend); Some(None) if the key has been deleted. Some(Some(...)) for a key whose
+	/// value has been set.
+	pub fn child_storage(&self, child_info: &ChildInfo, key: &[u8]) -> Option<Option<&[u8]>> {
+		if let Some(map) = self.prospective.children_default.get(child_info.storage_key()) {
+			if let Some(val) = map.0.get(key) {
+				let size_read = val.value.as_ref().map(|x| x.len() as u64).unwrap_or(0);
+				self.stats.tally_read_modified(size_read);
+				return Some(val.value.as_ref().map(AsRef::as_ref));
+			}
+		}
+
+		if let Some(map) = self.committed.children_default.get(child_info.storage_key()) {
+			if let Some(val) = map.0.get(key) {
+				let size_read = val.value.as_ref().map(|x| x.len() as u64).unwrap_or(0);
+				self.stats.tally_read_modified(size_read);
+				return Some(val.value.as_ref().map(AsRef::as_ref));
+			}
+		}
+
+		None
+	}
+
+	/// Inserts the given key-value pair into the prospective change set.
+	///
+	/// `None` can be used to delete a value specified by the given key.
+	pub(crate) fn set_storage(&mut self, key: StorageKey, val: Option<StorageValue>) {
+		let size_write = val.as_ref().map(|x| x.len() as u64).unwrap_or(0);
+		self.stats.tally_write_overlay(size_write);
+		let extrinsic_index = self.extrinsic_index();
+		self.note_last_write(&key);
+		#[cfg(feature = "audit")]
+		self.record_write(None, &key, val.as_ref(), extrinsic_index);
+		self.prospective.set(key, val, extrinsic_index);
+	}
+
+	/// Append the given encoded item to the encoded list stored under the given top level key.
+	///
+	/// `init` provides the current value if the overlay holds none. See
+	/// [`value_mut_or_insert_with`](#method.value_mut_or_insert_with).
+	pub(crate) fn append_storage(
+		&mut self,
+		key: &[u8],
+		value: StorageValue,
+		init: impl Fn() -> StorageValue,
+	) {
+		#[cfg(feature = "audit")]
+		let audit = self.audit_log.as_ref()
+			.map(|_| (self.audited_value(None, key), self.extrinsic_index()));
+		self.stats.tally_write_overlay(value.len() as u64);
+		let current_value = self.prospective_value_mut(key, init);
+		crate::ext::StorageAppend::new(current_value).append(value);
+		#[cfg(feature = "audit")]
+		{
+			if let Some((old_value, extrinsic)) = audit {
+				let entry = AuditEntry {
+					key: key.to_vec(),
+					child_storage_key: None,
+					old_value,
+					new_value: self.audited_value(None, key),
+					extrinsic,
+				};
+				self.audit_log.as_mut().expect("Checked above; qed").push(entry);
+			}
+		}
+	}
+
+	/// Replay a persisted write log into the prospective change set.
+	///
+	/// Each log entry carries the generation at which it was written. Only entries with a
+	/// generation greater or equal to `from_generation` are applied, so replaying the same log
+	/// after a restart skips the writes that were already applied.
+	pub fn replay_from_log(
+		&mut self,
+		log: &[(StorageKey, Option<StorageValue>, u64)],
+		from_generation: u64,
+	) {
+		for (key, val, generation) in log {
+			if *generation >= from_generation {
+				self.set_storage(key.clone(), val.clone());
+			}
+		}
+	}
+
+	/// Write all changes of `other` into the prospective change set.
+	///
+	/// The changes are tagged with the current extrinsic index like any other write.
+	pub fn import_committed(&mut self, other: OverlayedChangeSet) {
+		for (key, val) in other.top {
+			self.set_storage(key, val.value);
+		}
+		for (_, (map, child_info)) in other.children_default {
+			for (key, val) in map {
+				self.set_child_storage(&child_info, key, val.value);
+			}
+		}
+	}
+
+	/// Inserts the given key-value pair into the prospective child change set.
+	///
+	/// `None` can be used to delete a value specified by the given key.
+	pub(crate) fn set_child_storage(
+		&mut self,
+		child_info: &ChildInfo,
+		key: StorageKey,
+		val: Option<StorageValue>,
+	) {
+		let size_write = val.as_ref().map(|x| x.len() as u64).unwrap_or(0);
+		self.stats.tally_write_overlay(size_write);
+		let extrinsic_index = self.extrinsic_index();
+		#[cfg(feature = "audit")]
+		self.record_write(Some(child_info), &key, val.as_ref(), extrinsic_index);
+		let storage_key = child_info.storage_key().to_vec();
+		let map_entry = self.prospective.children_default.entry(storage_key)
+			.or_insert_with(|| (Default::default(), child_info.to_owned()));
+		let updatable = map_entry.1.try_update(child_info);
+		debug_assert!(updatable);
+
+		let entry = map_entry.0.entry(key).or_default();
+		entry.value = val;
+
+		if let Some(extrinsic) = extrinsic_index {
+			entry.extrinsics.get_or_insert_with(Default::default)
+				.insert(extrinsic);
+		}
+	}
+
+	/// Clear child storage of given storage key.
+	///
+	/// NOTE that this doesn't take place immediately but written into the prospective
+	/// change set, and still can be reverted by [`discard_prospective`].
+	///
+	/// [`discard_prospective`]: #method.discard_prospective
+	pub(crate) fn clear_child_storage(
+		&mut self,
+		child_info: &ChildInfo,
+	) {
+		let extrinsic_index = self.extrinsic_index();
+		#[cfg(feature = "audit")]
+		self.record_clear(Some(child_info), &[], extrinsic_index);
+		let storage_key = child_info.storage_key();
+		let map_entry = self.prospective.children_default.entry(storage_key.to_vec())
+			.or_insert_with(|| (Default::default(), child_info.to_owned()));
+		let updatable = map_entry.1.try_update(child_info);
+		debug_assert!(updatable);
+
+		let mut cleared = map_entry.0.len();
+		map_entry.0.values_mut().for_each(|e| {
+			if let Some(extrinsic) = extrinsic_index {
+				e.extrinsics.get_or_insert_with(Default::default)
+					.insert(extrinsic);
+			}
+
+			e.value = None;
+		});
+
+		if let Some((committed_map, _child_info)) = self.committed.children_default.get(storage_key) {
+			for (key, value) in committed_map.iter() {
+				if !map_entry.0.contains_key(key) {
+					cleared += 1;
+					map_entry.0.insert(key.clone(), OverlayedValue {
+						value: None,
+						extrinsics: extrinsic_index.map(|i| {
+							let mut e = value.extrinsics.clone()
+								.unwrap_or_else(|| BTreeSet::default());
+							e.insert(i);
+							e
+						}),
+					});
+				}
+			}
+		}
+
+		self.tally_clears(cleared);
+	}
+
+	/// Removes all key-value pairs which keys share the given prefix.
+	///
+	/// NOTE that this doesn't take place immediately but written into the prospective
+	/// change set, and still can be reverted by [`discard_prospective`].
+	///
+	/// [`discard_prospective`]: #method.discard_prospective
+	pub(crate) fn clear_prefix(&mut self, prefix: &[u8]) {
+		let extrinsic_index = self.extrinsic_index();
+		#[cfg(feature = "audit")]
+		self.record_clear(None, prefix, extrinsic_index);
+
+		let mut cleared = 0;
+
+		// Iterate over all prospective and mark all keys that share
+		// the given prefix as removed (None).
+		for (key, entry) in self.prospective.top.iter_mut() {
+			if key.starts_with(prefix) {
+				entry.value = None;
+				cleared += 1;
+
+				if let Some(extrinsic) = extrinsic_index {
+					entry.extrinsics.get_or_insert_with(Default::default)
+						.insert(extrinsic);
+				}
+			}
+		}
+
+		// Then do the same with keys from committed changes.
+		// NOTE that we are making changes in the prospective change set.
+		for key in self.committed.top.keys() {
+			if key.starts_with(prefix) && !self.prospective.top.contains_key(key) {
+				let entry = self.prospective.top.entry(key.clone()).or_default();
+				entry.value = None;
+				cleared += 1;
+				self.prospective.written_keys += 1;
+
+				if let Some(extrinsic) = extrinsic_index {
+					entry.extrinsics.get_or_insert_with(Default::default)
+						.insert(extrinsic);
+				}
+			}
+		}
+
+		self.prospective.count_writes(cleared, extrinsic_index);
+		self.tally_clears(cleared);
+	}
+
+	pub(crate) fn clear_child_prefix(
+		&mut self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+	) {
+		let extrinsic_index = self.extrinsic_index();
+		#[cfg(feature = "audit")]
+		self.record_clear(Some(child_info), prefix, extrinsic_index);
+		let storage_key = child_info.storage_key();
+		let map_entry = self.prospective.children_default.entry(storage_key.to_vec())
+			.or_insert_with(|| (Default::default(), child_info.to_owned()));
+		let updatable = map_entry.1.try_update(child_info);
+		debug_assert!(updatable);
+
+		let mut cleared = 0;
+		for (key, entry) in map_entry.0.iter_mut() {
+			if key.starts_with(prefix) {
+				entry.value = None;
+				cleared += 1;
+
+				if let Some(extrinsic) = extrinsic_index {
+					entry.extrinsics.get_or_insert_with(Default::default)
+						.insert(extrinsic);
+				}
+			}
+		}
+
+		if let Some((child_committed, _child_info)) = self.committed.children_default.get(storage_key) {
+			// Then do the same with keys from committed changes.
+			// NOTE that we are making changes in the prospective change set.
+			for key in child_committed.keys() {
+				if key.starts_with(prefix) {
+					if !map_entry.0.contains_key(key) {
+						cleared += 1;
+					}
+					let entry = map_entry.0.entry(key.clone()).or_default();
+					entry.value = None;
+
+					if let Some(extrinsic) = extrinsic_index {
+						entry.extrinsics.get_or_insert_with(Default::default)
+							.insert(extrinsic);
+					}
+				}
+			}
+		}
+
+		self.tally_clears(cleared);
+	}
+
+	/// Start recording the top level keys merged by each [`commit_prospective`].
+	///
+	/// [`commit_prospective`]: #method.commit_prospective
+	pub fn enable_last_commit_tracking(&mut self) {
+		self.last_commit_keys.get_or_insert_with(Vec::new);
+	}
+
+	/// Returns the top level keys merged by the last [`commit_prospective`].
+	///
+	/// Returns `None` if tracking was not enabled with [`enable_last_commit_tracking`].
+	///
+	/// [`commit_prospective`]: #method.commit_prospective
+	/// [`enable_last_commit_tracking`]: #method.enable_last_commit_tracking
+	pub fn keys_added_in_last_commit(&self) -> Option<&[StorageKey]> {
+		self.last_commit_keys.as_ref().map(|keys| &keys[..])
+	}
+
+	/// Release the memory over-allocated by the prospective and committed change sets.
+	///
+	/// Committing drains the prospective child changes but keeps their allocation, so this is
+	/// useful for long running nodes that reuse the same overlay.
+	pub fn shrink_to_fit(&mut self) {
+		self.prospective.shrink_to_fit();
+		self.committed.shrink_to_fit();
+	}
+
+	/// Call `observer` whenever prospective changes are committed or discarded.
+	///
+	/// A [`conditional_commit`] reports a single event, depending on whether the changes were
+	/// committed. [`simulate_commit`] reports nothing. Clones of the overlay share the
+	/// observer.
+	///
+	/// [`conditional_commit`]: #method.conditional_commit
+	/// [`simulate_commit`]: #method.simulate_commit
+	pub fn with_transaction_observer(
+		mut self,
+		observer: impl Fn(TransactionEvent) + Send + Sync + 'static,
+	) -> Self {
+		self.transaction_observer = Some(TransactionObserver(Arc::new(observer)));
+		self
+	}
+
+	/// Report `event` to the transaction observer, if any.
+	fn notify_transaction_observer(&self, event: TransactionEvent) {
+		if let Some(observer) = self.transaction_observer.as_ref() {
+			(observer.0)(event);
+		}
+	}
+
+	/// Returns the summary of the prospective changes last committed or discarded.
+	///
+	/// Returns `None` if prospective changes were never committed nor discarded.
+	pub fn transaction_summary(&self) -> Option<&TransactionSummary> {
+		self.last_transaction_summary.as_ref()
+	}
+
+	/// Summarize the prospective changes, about to be committed if `committed` is `true` or
+	/// discarded otherwise.
+	fn summarize_prospective(&self, committed: bool) -> TransactionSummary {
+		let keys = self.transaction_rollback_cost();
+		let child_bytes_written = self.prospective.children_default.values()
+			.flat_map(|(map, _)| map.values())
+			.filter_map(|v| v.value.as_ref().map(|v| v.len()))
+			.sum::<usize>();
+		TransactionSummary {
+			keys_committed: if committed { keys } else { 0 },
+			keys_rolled_back: if committed { 0 } else { keys },
+			bytes_written: self.prospective.transaction_bytes_written() + child_bytes_written,
+			extrinsic_indices: self.prospective.transaction_extrinsic_union().into_iter().collect(),
+		}
+	}
+
+	/// Discard prospective changes to state.
+	pub fn discard_prospective(&mut self) {
+		let summary = self.summarize_prospective(false);
+		self.notify_transaction_observer(
+			TransactionEvent::RolledBack { keys_discarded: summary.keys_rolled_back },
+		);
+		self.last_transaction_summary = Some(summary);
+		self.prospective.clear();
+		self.rollbacks += 1;
+	}
+
+	/// Discard prospective changes to state, logging the given reason.
+	///
+	/// With the `profiling` feature, the reason is kept as well, see [`last_abort_reason`].
+	/// See [`discard_prospective`](#method.discard_prospective).
+	///
+	/// [`last_abort_reason`]: #method.last_abort_reason
+	pub fn abort_transaction(&mut self, reason: &'static str) {
+		log::debug!(target: "state", "Discarding prospective changes: {}", reason);
+		#[cfg(feature = "profiling")]
+		{
+			self.last_abort_reason = Some(reason);
+		}
+		self.discard_prospective();
+	}
+
+	/// Returns the reason given to the last [`abort_transaction`], if any.
+	///
+	/// [`abort_transaction`]: #method.abort_transaction
+	#[cfg(feature = "profiling")]
+	pub fn last_abort_reason(&self) -> Option<&'static str> {
+		self.last_abort_reason
+	}
+
+	/// Discard all prospective and committed changes.
+	///
+	/// This leaves an empty overlay behind, e.g. to retry the execution of a block from
+	/// scratch. Settings like extrinsics collection are kept.
+	pub fn rollback_all_and_reset(&mut self) {
+		self.discard_prospective();
+		self.committed.clear();
+	}
+
+	/// Commit prospective changes to state.
+	pub fn commit_prospective(&mut self) {
+		self.commits += 1;
+		let summary = self.summarize_prospective(true);
+		self.notify_transaction_observer(
+			TransactionEvent::Committed { keys_merged: summary.keys_committed },
+		);
+		self.last_transaction_summary = Some(summary);
+		if let Some(keys) = self.last_commit_keys.as_mut() {
+			keys.clear();
+			keys.extend(self.prospective.top.keys().cloned());
+		}
+
+		self.prospective.commit_statistics(&mut self.committed);
+		if self.committed.is_empty() {
+			mem::swap(&mut self.prospective.top, &mut self.committed.top);
+			mem::swap(&mut self.prospective.children_default, &mut self.committed.children_default);
+		} else {
+			let top_to_commit = mem::replace(&mut self.prospective.top, BTreeMap::new());
+			for (key, val) in top_to_commit.into_iter() {
+				let entry = self.committed.top.entry(key).or_default();
+				entry.value = val.value;
+
+				if let Some(prospective_extrinsics) = val.extrinsics {
+					entry.extrinsics.get_or_insert_with(Default::default)
+						.extend(prospective_extrinsics);
+				}
+			}
+			for (storage_key, (map, child_info)) in self.prospective.children_default.drain() {
+				let child_content = self.committed.children_default.entry(storage_key)
+					.or_insert_with(|| (Default::default(), child_info));
+				// No update to child info at this point (will be needed for deletion).
+				for (key, val) in map.into_iter() {
+					let entry = child_content.0.entry(key).or_default();
+					entry.value = val.value;
+
+					if let Some(prospective_extrinsics) = val.extrinsics {
+						entry.extrinsics.get_or_insert_with(Default::default)
+							.extend(prospective_extrinsics);
+					}
+				}
+			}
+		}
+	}
+
+	/// Returns the committed changes as they would be after [`commit_prospective`], without
+	/// modifying the overlay.
+	///
+	/// Returns `None` if there are no prospective changes.
+	///
+	/// [`commit_prospective`]: #method.commit_prospective
+	pub fn simulate_commit(&self) -> Option<OverlayedChangeSet> {
+		if !self.has_uncommitted_changes() {
+			return None;
+		}
+		let mut overlay = self.clone();
+		overlay.transaction_observer = None;
+		overlay.commit_prospective();
+		Some(overlay.committed)
+	}
+
+	/// Returns the committed changes as they would be after [`discard_prospective`], without
+	/// modifying the overlay.
+	///
+	/// Returns `None` if there are no prospective changes.
+	///
+	/// [`discard_prospective`]: #method.discard_prospective
+	pub fn simulate_rollback(&self) -> Option<OverlayedChangeSet> {
+		if !self.has_uncommitted_changes() {
+			return None;
+		}
+		Some(self.committed.clone())
+	}
+
+	/// Returns the `blake2_256` hash of the state the prospective changes are applied on.
+	///
+	/// The hash covers the committed top level changes and the keys of the prospective top level
+	/// changes, both in key order. Returns `None` if there are no prospective changes.
+	pub fn transaction_pre_image_hash(&self) -> Option<[u8; 32]> {
+		if !self.has_uncommitted_changes() {
+			return None;
+		}
+		let committed = self.committed.top.iter()
+			.map(|(k, v)| (k, &v.value))
+			.collect::<Vec<_>>();
+		let prospective_keys = self.prospective.top.keys().collect::<Vec<_>>();
+		Some(sp_core::hashing::blake2_256(&(committed, prospective_keys).encode()))
+	}
+
+	/// Returns the top level changes that [`commit_prospective`] would make to the committed
+	/// changes, in key order, without modifying the overlay.
+	///
+	/// Prospective changes that set the value already committed are left out. Returns `None`
+	/// if there are no prospective changes.
+	///
+	/// [`commit_prospective`]: #method.commit_prospective
+	pub fn transaction_commit_preview(&self) -> Option<Vec<(StorageKey, Option<StorageValue>)>> {
+		if !self.has_uncommitted_changes() {
+			return None;
+		}
+		Some(self.prospective.top.iter()
+			.filter(|(k, v)| self.committed.top.get(*k).map(|c| &c.value) != Some(&v.value))
+			.map(|(k, v)| (k.clone(), v.value.clone()))
+			.collect())
+	}
+
+	/// Commit prospective changes to state if the resulting committed changes satisfy
+	/// `predicate`, discard them otherwise.
+	///
+	/// Returns `true` if the changes were committed. Only the committed changes overridden by
+	/// prospective ones are copied to be able to undo the commit.
+	pub fn conditional_commit(&mut self, predicate: impl Fn(&OverlayedChangeSet) -> bool) -> bool {
+		let undo = self.committed.commit_undo(&self.prospective);
+		let last_commit_keys = self.last_commit_keys.clone();
+		let keys = self.transaction_rollback_cost();
+		let observer = self.transaction_observer.take();
+		self.commit_prospective();
+		self.transaction_observer = observer;
+
+		if predicate(&self.committed) {
+			self.notify_transaction_observer(TransactionEvent::Committed { keys_merged: keys });
+			true
+		} else {
+			self.committed.undo_commit(undo);
+			self.last_commit_keys = last_commit_keys;
+			self.commits -= 1;
+			self.rollbacks += 1;
+			if let Some(summary) = self.last_transaction_summary.as_mut() {
+				summary.keys_rolled_back = mem::take(&mut summary.keys_committed);
+			}
+			self.notify_transaction_observer(TransactionEvent::RolledBack { keys_discarded: keys });
+			false
+		}
+	}
+
+	/// Consume `OverlayedChanges` and take committed set.
+	///
+	/// Panics:
+	/// Will panic if there are any uncommitted prospective changes.
+	fn drain_committed(&mut self) -> (
+		impl Iterator<Item=(StorageKey, Option<StorageValue>)>,
+		impl Iterator<Item=(StorageKey, (impl Iterator<Item=(StorageKey, Option<StorageValue>)>, ChildInfo))>,
+	) {
+		assert!(
+			!self.has_uncommitted_changes(),
+			"Draining committed changes with uncommitted prospective changes",
+		);
+		(
+			std::mem::take(&mut self.committed.top)
+				.into_iter()
+				.map(|(k, v)| (k, v.value)),
+			std::mem::take(&mut self.committed.children_default)
+				.into_iter()
+				.map(|(sk, (v, ci))| (sk, (v.into_iter().map(|(k, v)| (k, v.value)), ci))),
+		)
+	}
+
+	/// Get an iterator over all pending and committed child tries in the overlay.
+	pub fn child_infos(&self) -> impl IntoIterator<Item=&ChildInfo> {
+		self.committed.children_default.iter()
+			.chain(self.prospective.children_default.iter())
+			.map(|(_, v)| &v.1).collect::<BTreeSet<_>>()
+	}
+
+	/// Get an iterator over all pending and committed changes.
+	///
+	/// Supplying `None` for `child_info` will only return changes that are in the top
+	/// trie. Specifying some `child_info` will return only the changes in that
+	/// child trie.
+	pub fn changes(&self, child_info: Option<&ChildInfo>)
+		-> impl Iterator<Item=(&StorageKey, &OverlayedValue)>
+	{
+		let (committed, prospective) = if let Some(child_info) = child_info {
+			match child_info.child_type() {
+				ChildType::ParentKeyId => (
+					self.committed.children_default.get(child_info.storage_key()).map(|c| &c.0),
+					self.prospective.children_default.get(child_info.storage_key()).map(|c| &c.0),
+				),
+			}
+		} else {
+			(Some(&self.committed.top), Some(&self.prospective.top))
+		};
+		committed.into_iter().flatten().chain(prospective.into_iter().flatten())
+	}
+
+	/// Return a clone of the currently pending changes.
+	pub fn clone_pending(&self) -> OverlayedChangeSet {
+		self.prospective.clone()
+	}
+
+	/// Replace the currently pending changes.
+	pub fn replace_pending(&mut self, pending: OverlayedChangeSet) {
+		self.prospective = pending;
+	}
+
+	/// Execute `f` as the extrinsic with the given index, committing the changes it made if it
+	/// succeeds and discarding them if it fails.
+	///
+	/// If extrinsic indices are collected, the writes made by `f` are tagged with
+	/// `extrinsic_index` instead of the index found in storage. On `Err`, the last write key is
+	/// restored as well. Statistics and audit log entries are kept, as the writes did happen.
+	///
+	/// Changes that `f` commits itself stay committed, even if it fails afterwards.
+	///
+	/// Panics:
+	/// Will panic if there are any uncommitted prospective changes, as a failure of `f` would
+	/// discard them as well.
+	pub fn apply_extrinsic<R, E, F>(&mut self, extrinsic_index: u32, f: F) -> Result<R, E>
+		where F: FnOnce(&mut Self) -> Result<R, E>
+	{
+		assert!(
+			!self.has_uncommitted_changes(),
+			"Applying an extrinsic with uncommitted prospective changes",
+		);
+		let last_write_key = self.last_write_key.clone();
+
+		let applied_extrinsic = self.applied_extrinsic.replace(extrinsic_index);
+		let result = f(self);
+		self.applied_extrinsic = applied_extrinsic;
+
+		if result.is_ok() {
+			self.commit_prospective();
+		} else {
+			self.discard_prospective();
+			self.last_write_key = last_write_key;
+		}
+		result
+	}
+
+	/// Convert this instance with all changes into a [`StorageChanges`] instance.
+	pub fn into_storage_changes<
+		B: Backend<H>, H: Hasher, N: BlockNumber
+	>(
+		mut self,
+		backend: &B,
+		changes_trie_state: Option<&ChangesTrieState<H, N>>,
+		parent_hash: H::Out,
+		mut cache: StorageTransactionCache<B::Transaction, H, N>,
+	) -> Result<StorageChanges<B::Transaction, H, N>, String> where H::Out: Ord + Encode + 'static {
+		self.drain_storage_changes(backend, changes_trie_state, parent_hash, &mut cache)
+	}
+
+	/// Drain all changes into a [`StorageChanges`] instance. Leave empty overlay in place.
+	pub fn drain_storage_changes<B: Backend<H>, H: Hasher, N: BlockNumber>(
+		&mut self,
+		backend: &B,
+		changes_trie_state: Option<&ChangesTrieState<H, N>>,
+		parent_hash: H::Out,
+		mut cache: &mut StorageTransactionCache<B::Transaction, H, N>,
+	) -> Result<StorageChanges<B::Transaction, H, N>, String> where H::Out: Ord + Encode + 'static {
+		// If the transaction does not exist, we generate it.
+		if cache.transaction.is_none() {
+			self.storage_root(backend, &mut cache);
+		}
+
+		let (transaction, transaction_storage_root) = cache.transaction.take()
+			.and_then(|t| cache.transaction_storage_root.take().map(|tr| (t, tr)))
+			.expect("Transaction was be generated as part of `storage_root`; qed");
+
+		// If the transaction does not exist, we generate it.
+		if cache.changes_trie_transaction.is_none() {
+			self.changes_trie_root(
+				backend,
+				changes_trie_state,
+				parent_hash,
+				false,
+				&mut cache,
+			).map_err(|_| "Failed to generate changes trie transaction")?;
+		}
+
+		let changes_trie_transaction = cache.changes_trie_transaction
+			.take()
+			.expect("Changes trie transaction was generated by `changes_trie_root`; qed");
+
+		let offchain_storage_changes = Default::default();
+		let (main_storage_changes, child_storage_changes) = self.drain_committed();
+
+		Ok(StorageChanges {
+			main_storage_changes: main_storage_changes.collect(),
+			child_storage_changes: child_storage_changes.map(|(sk, it)| (sk, it.0.collect())).collect(),
+			offchain_storage_changes,
+			transaction,
+			transaction_storage_root,
+			changes_trie_transaction,
+		})
+	}
+
+	/// Inserts storage entry responsible for current extrinsic index.
+	#[cfg(test)]
+	pub(crate) fn set_extrinsic_index(&mut self, extrinsic_index: u32) {
+		self.prospective.top.insert(EXTRINSIC_INDEX.to_vec(), OverlayedValue {
+			value: Some(extrinsic_index.encode()),
+			extrinsics: None,
+		});
+	}
+
+	/// Returns current extrinsic index to use in changes trie construction.
+	/// None is returned if it is not set or changes trie config is not set.
+	/// Persistent value (from the backend) can be ignored because runtime must
+	/// set this index before first and unset after last extrinsic is executed.
+	/// Changes that are made outside of extrinsics, are marked with
+	/// `NO_EXTRINSIC_INDEX` index. Within [`apply_extrinsic`], the index
+	/// given to it is used.
+	///
+	/// [`apply_extrinsic`]: #method.apply_extrinsic
+	fn extrinsic_index(&self) -> Option<u32> {
+		match self.collect_extrinsics {
+			true => Some(self.applied_extrinsic.unwrap_or_else(||
+				self.storage(EXTRINSIC_INDEX)
+					.and_then(|idx| idx.and_then(|idx| Decode::decode(&mut &*idx).ok()))
+					.unwrap_or(NO_EXTRINSIC_INDEX)
+			)),
+			false => None,
+		}
+	}
+
+	/// Generate the storage root using `backend` and all changes from `prospective` and `committed`.
+	///
+	/// Returns the storage root and caches storage transaction in the given `cache`.
+	pub fn storage_root<H: Hasher, N: BlockNumber, B: Backend<H>>(
+		&self,
+		backend: &B,
+		cache: &mut StorageTransactionCache<B::Transaction, H, N>,
+	) -> H::Out
+		where H::Out: Ord + Encode,
+	{
+		let child_storage_keys = self.prospective.children_default.keys()
+				.chain(self.committed.children_default.keys());
+		let child_delta_iter = child_storage_keys.map(|storage_key|
+			(
+				self.default_child_info(storage_key)
+					.expect("child info initialized in either committed or prospective"),
+				self.committed.children_default.get(storage_key)
+					.into_iter()
+					.flat_map(|(map, _)|
+						map.iter().map(|(k, v)| (&k[..], v.value().map(|v| &v[..])))
+					)
+					.chain(
+						self.prospective.children_default.get(storage_key)
+							.into_iter()
+							.flat_map(|(map, _)|
+								map.iter().map(|(k, v)| (&k[..], v.value().map(|v| &v[..])))
+							)
+					),
+			)
+		);
+
+		// compute and memoize
+		let delta = self.committed
+			.top
+			.iter()
+			.map(|(k, v)| (&k[..], v.value().map(|v| &v[..])))
+			.chain(self.prospective.top.iter().map(|(k, v)| (&k[..], v.value().map(|v| &v[..]))));
+
+		let (root, transaction) = backend.full_storage_root(delta, child_delta_iter);
+
+		cache.transaction = Some(transaction);
+		cache.transaction_storage_root = Some(root);
+
+		root
+	}
+
+	/// Generate the changes trie root.
+	///
+	/// Returns the changes trie root and caches the storage transaction into the given `cache`.
+	///
+	/// # Panics
+	///
+	/// Panics on storage error, when `panic_on_storage_error` is set.
+	pub fn changes_trie_root<'a, H: Hasher, N: BlockNumber, B: Backend<H>>(
+		&self,
+		backend: &B,
+		changes_trie_state: Option<&'a ChangesTrieState<'a, H, N>>,
+		parent_hash: H::Out,
+		panic_on_storage_error: bool,
+		cache: &mut StorageTransactionCache<B::Transaction, H, N>,
+	) -> Result<Option<H::Out>, ()> where H::Out: Ord + Encode + 'static {
+		build_changes_trie::<_, H, N>(
+			backend,
+			changes_trie_state,
+			self,
+			parent_hash,
+			panic_on_storage_error,
+		).map(|r| {
+			let root = r.as_ref().map(|r| r.1).clone();
+			cache.changes_trie_transaction = Some(r.map(|(db, _, cache)| (db, cache)));
+			cache.changes_trie_transaction_storage_root = Some(root);
+			root
+		})
+	}
+
+	/// Get child info for a storage key.
+	/// Take the latest value so prospective first.
+	pub fn default_child_info(&self, storage_key: &[u8]) -> Option<&ChildInfo> {
+		if let Some((_, ci)) = self.prospective.children_default.get(storage_key) {
+			return Some(&ci);
+		}
+		if let Some((_, ci)) = self.committed.children_default.get(storage_key) {
+			return Some(&ci);
+		}
+		None
+	}
+
+	/// Returns the next (in lexicographic order) storage key in the overlayed alongside its value.
+	/// If no value is next then `None` is returned.
+	pub fn next_storage_key_change(&self, key: &[u8]) -> Option<(&[u8], &OverlayedValue)> {
+		let range = (ops::Bound::Excluded(key), ops::Bound::Unbounded);
+
+		let next_prospective_key = self.prospective.top
+			.range::<[u8], _>(range)
+			.next()
+			.map(|(k, v)| (&k[..], v));
+
+		let next_committed_key = self.committed.top
+			.range::<[u8], _>(range)
+			.next()
+			.map(|(k, v)| (&k[..], v));
+
+		match (next_committed_key, next_prospective_key) {
+			// Committed is strictly less than prospective
+			(Some(committed_key), Some(prospective_key)) if committed_key.0 < prospective_key.0 =>
+				Some(committed_key),
+			(committed_key, None) => committed_key,
+			// Prospective key is less or equal to committed or committed doesn't exist
+			(_, prospective_key) => prospective_key,
+		}
+	}
+
+	/// Returns the next (in lexicographic order) child storage key in the overlayed alongside its
+	/// value.  If no value is next then `None` is returned.
+	pub fn next_child_storage_key_change(
+		&self,
+		storage_key: &[u8],
+		key: &[u8]
+	) -> Option<(&[u8], &OverlayedValue)> {
+		let range = (ops::Bound::Excluded(key), ops::Bound::Unbounded);
+
+		let next_prospective_key = self.prospective.children_default.get(storage_key)
+			.and_then(|(map, _)| map.range::<[u8], _>(range).next().map(|(k, v)| (&k[..], v)));
+
+		let next_committed_key = self.committed.children_default.get(storage_key)
+			.and_then(|(map, _)| map.range::<[u8], _>(range).next().map(|(k, v)| (&k[..], v)));
+
+		match (next_committed_key, next_prospective_key) {
+			// Committed is strictly less than prospective
+			(Some(committed_key), Some(prospective_key)) if committed_key.0 < prospective_key.0 =>
+				Some(committed_key),
+			(committed_key, None) => committed_key,
+			// Prospective key is less or equal to committed or committed doesn't exist
+			(_, prospective_key) => prospective_key,
+		}
+	}
+}
+
+#[cfg(test)]
+impl From<Option<StorageValue>> for OverlayedValue {
+	fn from(value: Option<StorageValue>) -> OverlayedValue {
+		OverlayedValue { value, ..Default::default() }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use hex_literal::hex;
+	use sp_core::{
+		Blake2Hasher, traits::Externalities, storage::well_known_keys::EXTRINSIC_INDEX,
+	};
+	use crate::InMemoryBackend;
+	use crate::ext::Ext;
+	use super::*;
+
+	fn strip_extrinsic_index(map: &BTreeMap<StorageKey, OverlayedValue>)
+		-> BTreeMap<StorageKey, OverlayedValue>
+	{
+		let mut clone = map.clone();
+		clone.remove(&EXTRINSIC_INDEX.to_vec());
+		clone
+	}
+
+	#[test]
+	fn overlayed_storage_works() {
+		let mut overlayed = OverlayedChanges::default();
+
+		let key = vec![42, 69, 169, 142];
+
+		assert!(overlayed.storage(&key).is_none());
+
+		overlayed.set_storage(key.clone(), Some(vec![1, 2, 3]));
+		assert_eq!(overlayed.storage(&key).unwrap(), Some(&[1, 2, 3][..]));
+
+		overlayed.commit_prospective();
+		assert_eq!(overlayed.storage(&key).unwrap(), Some(&[1, 2, 3][..]));
+
+		overlayed.set_storage(key.clone(), Some(vec![]));
+		assert_eq!(overlayed.storage(&key).unwrap(), Some(&[][..]));
+
+		overlayed.set_storage(key.clone(), None);
+		assert!(overlayed.storage(&key).unwrap().is_none());
+
+		overlayed.discard_prospective();
+		assert_eq!(overlayed.storage(&key).unwrap(), Some(&[1, 2, 3][..]));
+
+		overlayed.set_storage(key.clone(), None);
+		overlayed.commit_prospective();
+		assert!(overlayed.storage(&key).unwrap().is_none());
+	}
+
+	#[test]
+	fn overlayed_storage_root_works() {
+		let initial: BTreeMap<_, _> = vec![
+			(b"doe".to_vec(), b"reindeer".to_vec()),
+			(b"dog".to_vec(), b"puppyXXX".to_vec()),
+			(b"dogglesworth".to_vec(), b"catXXX".to_vec()),
+			(b"doug".to_vec(), b"notadog".to_vec()),
+		].into_iter().collect();
+		let backend = InMemoryBackend::<Blake2Hasher>::from(initial);
+		let mut overlay = OverlayedChanges {
+			committed: vec![
+				(b"dog".to_vec(), Some(b"puppy".to_vec()).into()),
+				(b"dogglesworth".to_vec(), Some(b"catYYY".to_vec()).into()),
+				(b"doug".to_vec(), Some(vec![]).into()),
+			].into_iter().collect(),
+			prospective: vec![
+				(b"dogglesworth".to_vec(), Some(b"cat".to_vec()).into()),
+				(b"doug".to_vec(), None.into()),
+			].into_iter().collect(),
+			..Default::default()
+		};
+
+		let mut offchain_overlay = Default::default();
+		let mut cache = StorageTransactionCache::default();
+		let mut ext = Ext::new(
+			&mut overlay,
+			&mut offchain_overlay,
+			&mut cache,
+			&backend,
+			crate::changes_trie::disabled_state::<_, u64>(),
+			None,
+		);
+		const ROOT: [u8; 32] = hex!("39245109cef3758c2eed2ccba8d9b370a917850af3824bc8348d505df2c298fa");
+
+		assert_eq!(&ext.storage_root()[..], &ROOT);
+	}
+
+	#[test]
+	fn apply_genesis_works() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut genesis = Storage::default();
+		genesis.top.insert(b"doe".to_vec(), b"reindeer".to_vec());
+		genesis.top.insert(b"dog".to_vec(), b"puppy".to_vec());
+		genesis.children_default.insert(
+			child_info.storage_key().to_vec(),
+			StorageChild {
+				data: vec![(b"cat".to_vec(), b"kitten".to_vec())].into_iter().collect(),
+				child_info: child_info.clone(),
+			},
+		);
+		let expected = InMemoryBackend::<Blake2Hasher>::from(genesis.clone()).root().clone();
+
+		let mut committed = OverlayedChangeSet::default();
+		committed.apply_genesis(genesis);
+		assert_eq!(committed.value_at(b"dog"), Some(&b"puppy".to_vec()));
+
+		let backend = InMemoryBackend::<Blake2Hasher>::default();
+		let mut overlay = OverlayedChanges { committed, ..Default::default() };
+		let mut offchain_overlay = Default::default();
+		let mut cache = StorageTransactionCache::default();
+		let mut ext = Ext::new(
+			&mut overlay,
+			&mut offchain_overlay,
+			&mut cache,
+			&backend,
+			crate::changes_trie::disabled_state::<_, u64>(),
+			None,
+		);
+
+		assert_eq!(&ext.storage_root()[..], expected.as_ref());
+	}
+
+	#[test]
+	fn extrinsic_changes_are_collected() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+
+		overlay.set_storage(vec![100], Some(vec![101]));
+
+		overlay.set_extrinsic_index(0);
+		overlay.set_storage(vec![1], Some(vec![2]));
+
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![3], Some(vec![4]));
+
+		overlay.set_extrinsic_index(2);
+		overlay.set_storage(vec![1], Some(vec![6]));
+
+		assert_eq!(strip_extrinsic_index(&overlay.prospective.top),
+			vec![
+				(vec![1], OverlayedValue { value: Some(vec![6]),
+				 extrinsics: Some(vec![0, 2].into_iter().collect()) }),
+				(vec![3], OverlayedValue { value: Some(vec![4]),
+				 extrinsics: Some(vec![1].into_iter().collect()) }),
+				(vec![100], OverlayedValue { value: Some(vec![101]),
+				 extrinsics: Some(vec![NO_EXTRINSIC_INDEX].into_iter().collect()) }),
+			].into_iter().collect());
+
+		overlay.commit_prospective();
+
+		overlay.set_extrinsic_index(3);
+		overlay.set_storage(vec![3], Some(vec![7]));
+
+		overlay.set_extrinsic_index(4);
+		overlay.set_storage(vec![1], Some(vec![8]));
+
+		assert_eq!(strip_extrinsic_index(&overlay.committed.top),
+			vec![
+				(vec![1], OverlayedValue { value: Some(vec![6]),
+				 extrinsics: Some(vec![0, 2].into_iter().collect()) }),
+				(vec![3], OverlayedValue { value: Some(vec![4]),
+				 extrinsics: Some(vec![1].into_iter().collect()) }),
+				(vec![100], OverlayedValue { value: Some(vec![101]),
+				 extrinsics: Some(vec![NO_EXTRINSIC_INDEX].into_iter().collect()) }),
+			].into_iter().collect());
+
+		assert_eq!(strip_extrinsic_index(&overlay.prospective.top),
+			vec![
+				(vec![1], OverlayedValue { value: Some(vec![8]),
+				 extrinsics: Some(vec![4].into_iter().collect()) }),
+				(vec![3], OverlayedValue { value: Some(vec![7]),
+				 extrinsics: Some(vec![3].into_iter().collect()) }),
+			].into_iter().collect());
+
+		overlay.commit_prospective();
+
+		assert_eq!(strip_extrinsic_index(&overlay.committed.top),
+			vec![
+				(vec![1], OverlayedValue { value: Some(vec![8]),
+				 extrinsics: Some(vec![0, 2, 4].into_iter().collect()) }),
+				(vec![3], OverlayedValue { value: Some(vec![7]),
+				 extrinsics: Some(vec![1, 3].into_iter().collect()) }),
+				(vec![100], OverlayedValue { value: Some(vec![101]),
+				 extrinsics: Some(vec![NO_EXTRINSIC_INDEX].into_iter().collect()) }),
+			].into_iter().collect());
+
+		assert_eq!(overlay.prospective,
+			Default::default());
+	}
+
+	#[test]
+	fn discarded_extrinsic_index_is_not_committed() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.commit_prospective();
+		assert_eq!(overlay.committed.last_extrinsic_index(), Some(1));
+
+		overlay.set_extrinsic_index(5);
+		overlay.set_storage(vec![2], Some(vec![2]));
+		assert_eq!(overlay.prospective.last_extrinsic_index(), Some(5));
+		overlay.discard_prospective();
+		assert_eq!(overlay.prospective.last_extrinsic_index(), None);
+
+		overlay.commit_prospective();
+		assert_eq!(overlay.committed.last_extrinsic_index(), Some(1));
+	}
+
+	#[test]
+	fn key_deduplication_rate_counts_all_writes() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![1], Some(vec![2]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.value_mut_or_insert_with(&[2], Vec::new).push(3);
+		assert_eq!(overlay.prospective.transaction_key_deduplication_rate(), 0.5);
+
+		overlay.commit_prospective();
+		assert_eq!(overlay.committed.transaction_key_deduplication_rate(), 0.5);
+		assert_eq!(overlay.prospective.transaction_key_deduplication_rate(), 0.0);
+
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.clear_prefix(&[1]);
+		assert_eq!(overlay.prospective.transaction_key_deduplication_rate(), 0.0);
+
+		overlay.commit_prospective();
+		assert_eq!(overlay.committed.transaction_key_deduplication_rate(), 0.5);
+
+		let mut changeset = OverlayedChangeSet::default();
+		if let OverlayedEntry::Vacant(entry) = changeset.entry(vec![1]) {
+			entry.insert(Some(vec![1]));
+		}
+		if let OverlayedEntry::Occupied(mut entry) = changeset.entry(vec![1]) {
+			entry.insert(None);
+		}
+		assert_eq!(changeset.transaction_key_deduplication_rate(), 0.5);
+	}
+
+	#[test]
+	fn key_deduplication_rate_ignores_removals() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(vec![1], Some(vec![1]), None);
+		changeset.set(vec![2], Some(vec![2]), None);
+		changeset.set(vec![3], Some(vec![3]), None);
+		changeset.set(vec![4], None, None);
+		changeset.prune_by_predicate(|key, _| key == &[1]);
+		changeset.gc_tombstones(|_| false);
+		let _ = changeset.extract_range(&[3], &[4]);
+		if let OverlayedEntry::Occupied(entry) = changeset.entry(vec![2]) {
+			entry.remove();
+		}
+		assert!(changeset.is_empty());
+		assert_eq!(changeset.transaction_key_deduplication_rate(), 0.0);
+
+		changeset.set(vec![1], Some(vec![1]), None);
+		changeset.set(vec![1], Some(vec![2]), None);
+		assert_eq!(changeset.transaction_key_deduplication_rate(), 1.0 / 6.0);
+	}
+
+	#[cfg(feature = "audit")]
+	#[test]
+	fn audit_log_records_all_writes() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![9], Some(vec![9]));
+		overlay.enable_audit_log();
+
+		overlay.set_storage(vec![1, 1], Some(vec![1]));
+		overlay.commit_prospective();
+		overlay.append_storage(&[2], vec![7], Vec::new);
+		overlay.clear_prefix(&[1]);
+		overlay.set_child_storage(&child_info, vec![3], Some(vec![3]));
+		overlay.clear_child_storage(&child_info);
+
+		let entry = |
+			key: StorageKey,
+			child_storage_key: Option<StorageKey>,
+			old_value: Option<StorageValue>,
+			new_value: Option<StorageValue>,
+		| AuditEntry { key, child_storage_key, old_value, new_value, extrinsic: None };
+		let child_storage_key = Some(child_info.storage_key().to_vec());
+		assert_eq!(overlay.drain_audit_log(), vec![
+			entry(vec![1, 1], None, None, Some(vec![1])),
+			entry(vec![2], None, None, Some(vec![4, 7])),
+			entry(vec![1, 1], None, Some(vec![1]), None),
+			entry(vec![3], child_storage_key.clone(), None, Some(vec![3])),
+			entry(vec![3], child_storage_key, Some(vec![3]), None),
+		]);
+		assert!(overlay.drain_audit_log().is_empty());
+	}
+
+	#[test]
+	fn extrinsic_write_counts_include_appends_and_clears() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.append_storage(&[2], vec![2], Vec::new);
+		overlay.commit_prospective();
+
+		overlay.set_extrinsic_index(2);
+		overlay.value_mut_or_insert_with(&[2], Vec::new).push(3);
+		overlay.set_storage(vec![1, 1], Some(vec![1]));
+		overlay.clear_prefix(&[1]);
+		assert_eq!(
+			overlay.prospective.extrinsic_write_counts(),
+			vec![(2, 4)].into_iter().collect(),
+		);
+		assert_eq!(overlay.prospective.last_extrinsic_index(), Some(2));
+
+		overlay.commit_prospective();
+		assert_eq!(
+			overlay.committed.extrinsic_write_counts(),
+			vec![(1, 2), (2, 4)].into_iter().collect(),
+		);
+	}
+
+	#[test]
+	fn last_write_key_is_tracked_once_enabled() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		assert_eq!(overlay.last_write_key(), None);
+
+		overlay.enable_last_write_tracking();
+		overlay.set_storage(vec![2], Some(vec![2]));
+		assert_eq!(overlay.last_write_key(), Some(&vec![2]));
+
+		overlay.append_storage(&[3], vec![3], Vec::new);
+		assert_eq!(overlay.last_write_key(), Some(&vec![3]));
+
+		overlay.discard_prospective();
+		assert_eq!(overlay.last_write_key(), Some(&vec![3]));
+	}
+
+	#[test]
+	fn total_writes_since_creation_counts_all_writes() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1, 1], Some(vec![1]));
+		overlay.set_storage(vec![1, 2], None);
+		overlay.commit_prospective();
+		overlay.set_storage(vec![1, 3], Some(vec![3]));
+		assert_eq!(overlay.total_writes_since_creation(), 3);
+
+		overlay.append_storage(&[2], vec![2], Vec::new);
+		overlay.value_mut_or_insert_with(&[2], Vec::new).push(3);
+		assert_eq!(overlay.total_writes_since_creation(), 5);
+
+		overlay.clear_prefix(&[1]);
+		assert_eq!(overlay.total_writes_since_creation(), 8);
+
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+		overlay.commit_prospective();
+		overlay.set_child_storage(&child_info, vec![1, 1], Some(vec![1]));
+		overlay.clear_child_prefix(&child_info, &[1]);
+		assert_eq!(overlay.total_writes_since_creation(), 12);
+		overlay.set_child_storage(&child_info, vec![2], Some(vec![2]));
+		overlay.clear_child_storage(&child_info);
+		assert_eq!(overlay.total_writes_since_creation(), 16);
+
+		overlay.discard_prospective();
+		assert_eq!(overlay.total_writes_since_creation(), 16);
+	}
+
+	#[test]
+	fn apply_extrinsic_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.enable_last_write_tracking();
+		#[cfg(feature = "audit")]
+		overlay.enable_audit_log();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.commit_prospective();
+
+		let result: Result<u32, ()> = overlay.apply_extrinsic(3, |overlay| {
+			overlay.set_storage(vec![2], Some(vec![2]));
+			Ok(7)
+		});
+		assert_eq!(result, Ok(7));
+		assert!(!overlay.has_uncommitted_changes());
+		assert_eq!(
+			overlay.committed.top[&vec![1]].extrinsics,
+			Some(vec![NO_EXTRINSIC_INDEX].into_iter().collect()),
+		);
+		assert_eq!(overlay.committed.top[&vec![2]].extrinsics, Some(vec![3].into_iter().collect()));
+
+		let writes = overlay.total_writes_since_creation();
+		let result: Result<(), &str> = overlay.apply_extrinsic(4, |overlay| {
+			overlay.set_storage(vec![1], None);
+			overlay.set_storage(vec![3], Some(vec![3]));
+			Err("failed")
+		});
+		assert_eq!(result, Err("failed"));
+		assert!(!overlay.has_uncommitted_changes());
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.storage(&[3]), None);
+		assert_eq!(overlay.total_writes_since_creation(), writes + 2);
+		assert_eq!(overlay.last_write_key(), Some(&vec![2]));
+		assert_eq!(overlay.transaction_rolled_back_count(), 1);
+		#[cfg(feature = "audit")]
+		assert_eq!(overlay.drain_audit_log().len(), 4);
+	}
+
+	#[test]
+	#[should_panic(expected = "Applying an extrinsic with uncommitted prospective changes")]
+	fn apply_extrinsic_panics_on_uncommitted_changes() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		let _ = overlay.apply_extrinsic(0, |_| -> Result<(), ()> { Ok(()) });
+	}
+
+	#[test]
+	fn transaction_summary_works() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		assert_eq!(overlay.transaction_summary(), None);
+
+		overlay.set_extrinsic_index(1);
+		overlay.set_storage(vec![1], Some(vec![1, 1]));
+		overlay.set_storage(vec![2], None);
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1, 1, 1]));
+		overlay.commit_prospective();
+		// The extrinsic index is written as well.
+		let index_len = 1u32.encode().len();
+		assert_eq!(overlay.transaction_summary(), Some(&TransactionSummary {
+			keys_committed: 4,
+			keys_rolled_back: 0,
+			bytes_written: 5 + index_len,
+			extrinsic_indices: vec![1],
+		}));
+
+		overlay.set_storage(vec![1], Some(vec![2]));
+		overlay.discard_prospective();
+		assert_eq!(overlay.transaction_summary(), Some(&TransactionSummary {
+			keys_committed: 0,
+			keys_rolled_back: 1,
+			bytes_written: 1,
+			extrinsic_indices: vec![1],
+		}));
+
+		overlay.set_storage(vec![3], Some(vec![3]));
+		assert!(!overlay.conditional_commit(|_| false));
+		assert_eq!(overlay.transaction_summary().map(|s| s.keys_rolled_back), Some(1));
+		assert_eq!(overlay.transaction_summary().map(|s| s.keys_committed), Some(0));
+	}
+
+	#[test]
+	fn transaction_observer_works() {
+		let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+		let observed = events.clone();
+		let mut overlay = OverlayedChanges::default()
+			.with_transaction_observer(move |event| observed.lock().unwrap().push(event));
+
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_child_storage(&ChildInfo::new_default(b"child"), vec![1], Some(vec![1]));
+		overlay.commit_prospective();
+		overlay.set_storage(vec![2], Some(vec![2]));
+		assert!(overlay.simulate_commit().is_some());
+		overlay.discard_prospective();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		assert!(!overlay.conditional_commit(|_| false));
+		overlay.set_storage(vec![3], Some(vec![3]));
+		assert!(overlay.conditional_commit(|_| true));
+
+		assert_eq!(*events.lock().unwrap(), vec![
+			TransactionEvent::Committed { keys_merged: 2 },
+			TransactionEvent::RolledBack { keys_discarded: 1 },
+			TransactionEvent::RolledBack { keys_discarded: 1 },
+			TransactionEvent::Committed { keys_merged: 1 },
+		]);
+	}
+
+	#[test]
+	fn value_counts_work() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.commit_prospective();
+		assert_eq!(overlay.committed_value_count(), 2);
+		assert_eq!(overlay.open_value_count(), 0);
+		assert!(!overlay.is_write_amplified());
+
+		overlay.set_storage(vec![2], None);
+		overlay.set_storage(vec![3], Some(vec![3]));
+		assert_eq!(overlay.committed_value_count(), 1);
+		assert_eq!(overlay.open_value_count(), 1);
+		assert!(overlay.is_write_amplified());
+
+		overlay.commit_prospective();
+		assert_eq!(overlay.committed_value_count(), 3);
+		assert_eq!(overlay.open_value_count(), 0);
+	}
+
+	#[cfg(feature = "profiling")]
+	#[test]
+	fn overlay_reads_are_tracked() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.prospective.enable_access_tracking();
+		overlay.committed.enable_access_tracking();
+
+		overlay.set_storage(vec![1], Some(vec![1]));
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.prospective.access_frequency(&[1]), Some((1, 1)));
+
+		overlay.commit_prospective();
+		assert_eq!(overlay.prospective.access_frequency(&[1]), Some((1, 1)));
+		assert_eq!(overlay.committed.access_frequency(&[1]), None);
+
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.prospective.access_frequency(&[1]), Some((2, 1)));
+		assert_eq!(overlay.committed.access_frequency(&[1]), Some((1, 0)));
+	}
+
+	#[cfg(feature = "profiling")]
+	#[test]
+	fn last_abort_reason_is_kept() {
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(overlay.last_abort_reason(), None);
+
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.abort_transaction("invalid nonce");
+		assert_eq!(overlay.last_abort_reason(), Some("invalid nonce"));
+		assert_eq!(overlay.storage(&[1]), None);
+
+		overlay.discard_prospective();
+		overlay.abort_transaction("out of gas");
+		assert_eq!(overlay.last_abort_reason(), Some("out of gas"));
+		assert_eq!(overlay.transaction_rolled_back_count(), 3);
+	}
+
+	#[test]
+	fn entry_works() {
+		let mut changeset: OverlayedChangeSet = vec![
+			(vec![10], Some(vec![10]).into()),
+		].into_iter().collect();
+
+		match changeset.entry(vec![10]) {
+			OverlayedEntry::Occupied(mut entry) => {
+				assert_eq!(entry.key(), &vec![10]);
+				assert_eq!(entry.get(), Some(&vec![10]));
+				assert_eq!(entry.insert(None), Some(vec![10]));
+				assert_eq!(entry.get(), None);
+			},
+			OverlayedEntry::Vacant(_) => panic!("Key 10 has a change"),
+		}
+		assert_eq!(changeset.get(&[10]).map(|v| v.value()), Some(None));
+
+		match changeset.entry(vec![20]) {
+			OverlayedEntry::Vacant(entry) => {
+				assert_eq!(entry.key(), &vec![20]);
+				assert_eq!(entry.insert(Some(vec![20])).value(), Some(&vec![20]));
+			},
+			OverlayedEntry::Occupied(_) => panic!("Key 20 has no change"),
+		}
+
+		match changeset.entry(vec![10]) {
+			OverlayedEntry::Occupied(entry) => assert_eq!(entry.remove(), None),
+			OverlayedEntry::Vacant(_) => panic!("Key 10 has a change"),
+		}
+		assert!(changeset.get(&[10]).is_none());
+		assert_eq!(changeset.into_sorted_vec(), vec![(vec![20], Some(vec![20]))]);
+	}
+
+	#[test]
+	fn committed_and_uncommitted_views_work() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![10], Some(vec![10]));
+		overlay.set_storage(vec![20], Some(vec![20]));
+		overlay.commit_prospective();
+		overlay.set_storage(vec![20], None);
+		overlay.set_storage(vec![30], Some(vec![30]));
+
+		let committed = overlay.committed_view();
+		assert_eq!(committed.get(&[10]).and_then(|v| v.value()), Some(&vec![10]));
+		assert!(committed.get(&[20]).is_none());
+		assert!(committed.get(&[30]).is_none());
+		assert_eq!(
+			committed.changes().map(|(k, _)| k.clone()).collect::<Vec<_>>(),
+			vec![vec![10]],
+		);
+		assert_eq!(committed.next_change(&[5]).map(|(k, _)| k), Some(&[10][..]));
+		assert!(committed.next_change(&[10]).is_none());
+
+		let uncommitted = overlay.uncommitted_view();
+		assert_eq!(uncommitted.get(&[20]).map(|v| v.value()), Some(None));
+		assert!(uncommitted.get(&[10]).is_none());
+		assert_eq!(uncommitted.base(&[20]).and_then(|v| v.value()), Some(&vec![20]));
+		assert!(uncommitted.base(&[10]).is_none());
+		assert!(uncommitted.base(&[30]).is_none());
+		assert_eq!(
+			uncommitted.changes()
+				.map(|(k, v, base)| (k.clone(), v.value().cloned(), base.is_some()))
+				.collect::<Vec<_>>(),
+			vec![(vec![20], None, true), (vec![30], Some(vec![30]), false)],
+		);
+	}
+
+	#[test]
+	fn committed_btreemaps_work() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![10], Some(vec![10]));
+		overlay.set_storage(vec![20], None);
+		overlay.set_storage(vec![30], Some(vec![30]));
+		overlay.commit_prospective();
+		overlay.set_storage(vec![30], Some(vec![31]));
+		overlay.set_storage(vec![40], Some(vec![40]));
+
+		assert_eq!(
+			overlay.committed_to_btreemap(),
+			vec![(&vec![10], Some(&vec![10])), (&vec![20], None)].into_iter().collect(),
+		);
+		assert_eq!(
+			overlay.committed_into_btreemap(),
+			vec![(vec![10], Some(vec![10])), (vec![20], None)].into_iter().collect(),
+		);
+	}
+
+	#[test]
+	fn committed_keys_with_size_above_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![10], Some(vec![1; 10]));
+		overlay.set_storage(vec![20], Some(vec![1; 20]));
+		overlay.set_storage(vec![30], Some(vec![1; 30]));
+		overlay.set_storage(vec![40], None);
+		overlay.commit_prospective();
+		overlay.set_storage(vec![30], None);
+		overlay.set_storage(vec![50], Some(vec![1; 50]));
+
+		assert_eq!(
+			overlay.committed_keys_with_size_above(10).collect::<Vec<_>>(),
+			vec![(&vec![20], 20)],
+		);
+		assert_eq!(overlay.committed_keys_with_size_above(0).count(), 2);
+	}
+
+	#[test]
+	fn set_checked_and_try_set_work() {
+		let mut changeset = OverlayedChangeSet::default();
+		assert_eq!(changeset.set_checked(vec![1], Some(vec![1]), None), Ok(()));
+		assert_eq!(changeset.set_checked(vec![1], Some(vec![2]), None), Ok(()));
+
+		changeset.enable_write_once_mode();
+		assert_eq!(
+			changeset.set_checked(vec![1], Some(vec![3]), None),
+			Err(DoubleWriteError { key: vec![1] }),
+		);
+		changeset.assert_value(&[1], Some(&[2]));
+		assert_eq!(changeset.set_checked(vec![2], None, None), Ok(()));
+
+		changeset.seal();
+		assert_eq!(
+			changeset.try_set(vec![3], Some(vec![3]), None),
+			Err(SealedChangeSetError { key: vec![3] }),
+		);
+		assert!(changeset.get(&[3]).is_none());
+		assert_eq!(changeset.try_set(vec![1], Some(vec![4]), None), Ok(()));
+		assert_eq!(changeset.try_set(vec![3], None, None), Ok(()));
+
+		changeset.unseal();
+		assert_eq!(changeset.try_set(vec![4], Some(vec![4]), None), Ok(()));
+		changeset.assert_value(&[1], Some(&[4]));
+		changeset.assert_value(&[4], Some(&[4]));
+	}
+
+	#[test]
+	fn budget_guards_work() {
+		let mut changeset = OverlayedChangeSet::default();
+		let mut writes = changeset.transaction_write_budget_guard();
+		assert_eq!(writes.remaining(), None);
+		assert_eq!(writes.consume(usize::max_value()), Ok(()));
+
+		changeset.set_write_budget(2);
+		changeset.set_byte_budget(10);
+
+		let mut writes = changeset.transaction_write_budget_guard();
+		assert_eq!(writes.consume(1), Ok(()));
+		assert_eq!(writes.consume(2), Err(BudgetExhausted));
+		assert_eq!(writes.remaining(), Some(1));
+		assert_eq!(writes.consume(1), Ok(()));
+		assert_eq!(writes.remaining(), Some(0));
+
+		let mut bytes = changeset.byte_budget_guard();
+		assert_eq!(bytes.consume(10), Ok(()));
+		assert_eq!(bytes.consume(1), Err(BudgetExhausted));
+		assert_eq!(changeset.byte_budget_guard().remaining(), Some(10));
+	}
+
+	#[test]
+	fn histograms_work() {
+		let changeset: OverlayedChangeSet = vec![
+			(vec![], Some(vec![]).into()),
+			(vec![0, 1], Some(vec![1]).into()),
+			(vec![99], None.into()),
+			(vec![100], Some(vec![100]).into()),
+			(vec![255], Some(vec![255]).into()),
+		].into_iter().collect();
+
+		assert_eq!(changeset.key_histogram(100), vec![3, 1, 1]);
+		assert_eq!(changeset.key_histogram(256), vec![5]);
+		assert_eq!(changeset.key_histogram(1000), vec![5]);
+		assert_eq!(changeset.key_histogram(usize::max_value()), vec![5]);
+		assert_eq!(changeset.key_histogram(255), vec![4, 1]);
+		assert_eq!(changeset.key_histogram(1).len(), 256);
+
+		assert_eq!(changeset.write_histogram(3), vec![2, 2, 1]);
+		assert_eq!(changeset.write_histogram(1), vec![5]);
+
+		let histogram = changeset.write_histogram(512);
+		assert_eq!(histogram.len(), 512);
+		assert_eq!((histogram[0], histogram[198], histogram[200], histogram[510]), (2, 1, 1, 1));
+		assert_eq!(histogram.iter().sum::<usize>(), 5);
+
+		assert_eq!(OverlayedChangeSet::default().write_histogram(4), vec![0; 4]);
+	}
+
+	#[test]
+	fn conditional_commit_works() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_child_storage(&child_info, vec![1], Some(vec![1]));
+
+		assert!(overlay.conditional_commit(|committed| committed.value_at(&[1]).is_some()));
+		assert!(!overlay.has_uncommitted_changes());
+		overlay.committed.assert_value(&[1], Some(&[1]));
+		assert_eq!(overlay.transaction_committed_count(), 1);
+		assert_eq!(overlay.transaction_rolled_back_count(), 0);
+
+		let committed = overlay.committed.clone();
+		overlay.set_storage(vec![1], None);
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.set_child_storage(&child_info, vec![1], None);
+		overlay.set_child_storage(&ChildInfo::new_default(b"other"), vec![2], Some(vec![2]));
+
+		assert!(!overlay.conditional_commit(|committed| committed.value_at(&[1]).is_some()));
+		assert!(!overlay.has_uncommitted_changes());
+		assert_eq!(overlay.committed, committed);
+		assert_eq!(overlay.transaction_committed_count(), 1);
+		assert_eq!(overlay.transaction_rolled_back_count(), 1);
+	}
+
+	#[test]
+	fn sample_works() {
+		let changeset: OverlayedChangeSet = (0u8..20)
+			.map(|i| (vec![i], Some(vec![i]).into()))
+			.collect();
+
+		let sample = changeset.sample(5, 42);
+		assert_eq!(sample.len(), 5);
+		assert!(sample.windows(2).all(|pair| pair[0].0 < pair[1].0));
+		assert_eq!(changeset.sample(5, 42), sample);
+
+		assert_eq!(changeset.sample(30, 42), changeset.top.iter().collect::<Vec<_>>());
+		assert!(OverlayedChangeSet::default().sample(3, 42).is_empty());
+	}
+
+	#[test]
+	fn export_diff_works() {
+		let base: OverlayedChangeSet = vec![
+			(vec![10], Some(vec![10]).into()),
+			(vec![20], Some(vec![20]).into()),
+			(vec![30], None.into()),
+		].into_iter().collect();
+		let changeset: OverlayedChangeSet = vec![
+			(vec![10], Some(vec![10]).into()),
+			(vec![20], Some(vec![21]).into()),
+			(vec![25], None.into()),
+		].into_iter().collect();
+
+		assert_eq!(changeset.export_diff(&base), vec![
+			(vec![20], Some(vec![21])),
+			(vec![25], None),
+			(vec![30], None),
+		]);
+		assert!(base.export_diff(&base).is_empty());
+	}
+
+	#[test]
+	fn is_idempotent_replay_works() {
+		let changeset: OverlayedChangeSet = vec![
+			(vec![10], Some(vec![10]).into()),
+			(vec![20], None.into()),
+		].into_iter().collect();
+
+		assert!(changeset.is_idempotent_replay(&[]));
+		assert!(changeset.is_idempotent_replay(&[(vec![10], Some(vec![10])), (vec![20], None)]));
+		assert!(!changeset.is_idempotent_replay(&[(vec![10], Some(vec![11]))]));
+		assert!(!changeset.is_idempotent_replay(&[(vec![20], Some(vec![]))]));
+		assert!(!changeset.is_idempotent_replay(&[(vec![30], None)]));
+		changeset.assert_value(&[10], Some(&[10]));
+	}
+
+	#[test]
+	fn set_batch_atomic_works() {
+		let mut changeset = OverlayedChangeSet::default();
+		let writes = vec![(vec![10], Some(vec![10])), (vec![20], Some(vec![20]))];
+
+		assert!(!changeset.set_batch_atomic(writes.clone(), Some(1), |c| c.top.len() < 2));
+		assert!(changeset.is_empty());
+
+		assert!(changeset.set_batch_atomic(writes, Some(1), |c| c.top.len() == 2));
+		changeset.assert_value(&[10], Some(&[10]));
+		changeset.assert_value(&[20], Some(&[20]));
+		assert_eq!(changeset.extrinsic_write_counts(), vec![(1, 2)].into_iter().collect());
+	}
+
+	#[test]
+	fn compare_and_swap_works() {
+		let mut changeset = OverlayedChangeSet::default();
+		assert!(!changeset.compare_and_swap(vec![10], Some(&[1]), Some(vec![2]), None));
+		assert!(changeset.get(&[10]).is_none());
+
+		assert!(changeset.compare_and_swap(vec![10], None, Some(vec![1]), None));
+		assert!(!changeset.compare_and_swap(vec![10], Some(&[2]), Some(vec![3]), None));
+		changeset.assert_value(&[10], Some(&[1]));
+
+		assert!(changeset.compare_and_swap(vec![10], Some(&[1]), None, Some(2)));
+		assert_eq!(changeset.get(&[10]), Some(&OverlayedValue {
+			value: None,
+			extrinsics: Some(vec![2].into_iter().collect()),
+		}));
+	}
+
+	#[test]
+	fn swap_values_works() {
+		let mut changeset: OverlayedChangeSet = vec![
+			(vec![10], Some(vec![10]).into()),
+			(vec![20], Some(vec![20]).into()),
+		].into_iter().collect();
+
+		changeset.swap_values(vec![10], vec![20], Some(1));
+		changeset.assert_value(&[10], Some(&[20]));
+		changeset.assert_value(&[20], Some(&[10]));
+		assert_eq!(changeset.extrinsic_write_counts(), vec![(1, 2)].into_iter().collect());
+
+		changeset.swap_values(vec![10], vec![30], None);
+		assert_eq!(changeset.get(&[10]).map(|v| v.value()), Some(None));
+		changeset.assert_value(&[30], Some(&[20]));
+	}
+
+	#[test]
+	fn merge_extrinsic_data_works() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut source = OverlayedChangeSet::default();
+		source.set(vec![10], Some(vec![10]), Some(5));
+		source.set(vec![20], None, None);
+		source.children_default.insert(
+			child_info.storage_key().to_vec(),
+			(vec![(vec![30], Some(vec![30]).into())].into_iter().collect(), child_info.clone()),
+		);
+
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(vec![10], Some(vec![1]), Some(1));
+		changeset.merge_extrinsic_data(2, &source);
+
+		assert_eq!(changeset.get(&[10]), Some(&OverlayedValue {
+			value: Some(vec![10]),
+			extrinsics: Some(vec![1, 2].into_iter().collect()),
+		}));
+		assert_eq!(changeset.get(&[20]), Some(&OverlayedValue {
+			value: None,
+			extrinsics: Some(vec![2].into_iter().collect()),
+		}));
+		assert_eq!(
+			changeset.children_default[child_info.storage_key()].0.get(&vec![30]),
+			Some(&OverlayedValue {
+				value: Some(vec![30]),
+				extrinsics: Some(vec![2].into_iter().collect()),
+			}),
+		);
+	}
+
+	#[test]
+	fn iter_prefix_from_works() {
+		let changeset: OverlayedChangeSet = vec![
+			(vec![1], Some(vec![1]).into()),
+			(vec![2], Some(vec![2]).into()),
+			(vec![2, 1], None.into()),
+			(vec![2, 2], Some(vec![22]).into()),
+			(vec![3], Some(vec![3]).into()),
+		].into_iter().collect();
+		let keys = |start: &[u8]| changeset.iter_prefix_from(&[2], start)
+			.map(|(k, _)| k.clone())
+			.collect::<Vec<_>>();
+
+		assert_eq!(keys(&[]), vec![vec![2], vec![2, 1], vec![2, 2]]);
+		assert_eq!(keys(&[2]), vec![vec![2, 1], vec![2, 2]]);
+		assert_eq!(keys(&[2, 1]), vec![vec![2, 2]]);
+		assert!(keys(&[2, 2]).is_empty());
+		assert!(keys(&[3]).is_empty());
+	}
+
+	#[test]
+	fn committed_iter_from_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![10], Some(vec![10]));
+		overlay.set_storage(vec![20], None);
+		overlay.set_storage(vec![30], Some(vec![30]));
+		overlay.commit_prospective();
+		overlay.set_storage(vec![30], Some(vec![31]));
+
+		assert_eq!(
+			overlay.committed_iter_from(&[15]).collect::<Vec<_>>(),
+			vec![(&vec![20], None)],
+		);
+		assert_eq!(
+			overlay.committed_iter_from(&[10]).collect::<Vec<_>>(),
+			vec![(&vec![10], Some(&vec![10])), (&vec![20], None)],
+		);
+		assert_eq!(overlay.committed_iter_from(&[21]).count(), 0);
+	}
+
+	#[test]
+	fn iter_deleted_keys_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![10], None);
+		overlay.set_storage(vec![20], None);
+		overlay.set_storage(vec![30], Some(vec![30]));
+		overlay.commit_prospective();
+		overlay.set_storage(vec![20], Some(vec![20]));
+		overlay.set_storage(vec![30], None);
+		overlay.set_storage(vec![5], None);
+
+		assert_eq!(
+			overlay.iter_deleted_keys().collect::<Vec<_>>(),
+			vec![&vec![5], &vec![30], &vec![10]],
+		);
+	}
+
+	#[test]
+	fn to_storage_proof_works() {
+		let changeset: OverlayedChangeSet = vec![
+			(b"doe".to_vec(), Some(b"reindeer".to_vec()).into()),
+			(b"dog".to_vec(), Some(b"puppy".to_vec()).into()),
+			(b"dogglesworth".to_vec(), None.into()),
+		].into_iter().collect();
+		let values: BTreeMap<_, _> = vec![
+			(b"doe".to_vec(), b"reindeer".to_vec()),
+			(b"dog".to_vec(), b"puppy".to_vec()),
+		].into_iter().collect();
+		let root = InMemoryBackend::<Blake2Hasher>::from(values).root().clone();
+
+		let proof = changeset.to_storage_proof::<Blake2Hasher>();
+		let keys = vec![b"doe".to_vec(), b"dog".to_vec(), b"dogglesworth".to_vec()];
+		let read = crate::read_proof_check::<Blake2Hasher, _>(root, proof, keys).unwrap();
+		assert_eq!(read[&b"doe".to_vec()], Some(b"reindeer".to_vec()));
+		assert_eq!(read[&b"dog".to_vec()], Some(b"puppy".to_vec()));
+		assert_eq!(read[&b"dogglesworth".to_vec()], None);
+	}
+
+	#[test]
+	fn extract_range_works() {
+		let mut changeset: OverlayedChangeSet = vec![
+			(vec![10], Some(vec![10]).into()),
+			(vec![20], Some(vec![20]).into()),
+			(vec![20, 1], None.into()),
+			(vec![30], Some(vec![30]).into()),
+		].into_iter().collect();
+
+		let extracted = changeset.extract_range(&[20], &[30]);
+		changeset.assert_value(&[20], None);
+		extracted.assert_value(&[20], Some(&[20]));
+
+		assert_eq!(extracted, vec![
+			(vec![20], Some(vec![20]).into()),
+			(vec![20, 1], None.into()),
+		].into_iter().collect());
+		assert_eq!(changeset, vec![
+			(vec![10], Some(vec![10]).into()),
+			(vec![30], Some(vec![30]).into()),
+		].into_iter().collect());
+	}
+
+	#[test]
+	fn change_set_encode_decode_works() {
+		let changeset: OverlayedChangeSet = vec![
+			(vec![10], Some(vec![10]).into()),
+			(vec![20], None.into()),
+			(vec![30], Some(vec![]).into()),
+		].into_iter().collect();
+
+		let encoded = changeset.encode();
+		assert_eq!(
+			encoded,
+			vec![
+				(vec![10u8], Some(vec![10u8])),
+				(vec![20], None),
+				(vec![30], Some(vec![])),
+			].encode(),
+		);
+		assert_eq!(OverlayedChangeSet::decode(&mut &encoded[..]).unwrap(), changeset);
+	}
+
+	#[test]
+	fn value_at_works() {
+		let changeset: OverlayedChangeSet = vec![
+			(vec![1], Some(vec![1]).into()),
+			(vec![2], None.into()),
+		].into_iter().collect();
+
+		assert_eq!(changeset.value_at(&[1]), Some(&vec![1]));
+		assert_eq!(changeset.value_at(&[2]), None);
+		assert_eq!(changeset.value_at(&[3]), None);
+	}
+
+	#[test]
+	fn max_value_size_works() {
+		let changeset: OverlayedChangeSet = vec![
+			(vec![1], Some(vec![1, 2]).into()),
+			(vec![2], Some(vec![1, 2, 3]).into()),
+			(vec![3], None.into()),
+		].into_iter().collect();
+		assert_eq!(changeset.max_value_size(), 3);
+
+		let deleted: OverlayedChangeSet = vec![(vec![1], None.into())].into_iter().collect();
+		assert_eq!(deleted.max_value_size(), 0);
+		assert_eq!(OverlayedChangeSet::default().max_value_size(), 0);
+	}
+
+	#[test]
+	fn total_extrinsic_count_works() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(vec![1], Some(vec![1]), Some(0));
+		changeset.set(vec![2], Some(vec![2]), Some(0));
+		changeset.set(vec![2], None, Some(1));
+		changeset.set(vec![3], Some(vec![3]), None);
+		assert_eq!(changeset.total_extrinsic_count(), 2);
+
+		changeset.children_default.insert(child_info.storage_key().to_vec(), (
+			vec![(vec![1], OverlayedValue {
+				value: Some(vec![1]),
+				extrinsics: Some(vec![1, 2].into_iter().collect()),
+			})].into_iter().collect(),
+			child_info.clone(),
+		));
+		assert_eq!(changeset.total_extrinsic_count(), 3);
+		assert_eq!(OverlayedChangeSet::default().total_extrinsic_count(), 0);
+	}
+
+	#[test]
+	fn map_values_works() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(vec![1], Some(vec![1]), Some(0));
+		changeset.set(vec![2], Some(vec![2]), None);
+		changeset.set(vec![3], None, None);
+
+		changeset.map_values(|key, value| match key[0] {
+			1 => value.map(|mut value| { value.push(10); value }),
+			2 => None,
+			_ => Some(vec![30]),
+		});
+
+		changeset.assert_value(&[1], Some(&[1, 10]));
+		assert_eq!(changeset.top[&vec![1]].extrinsics, Some(vec![0].into_iter().collect()));
+		changeset.assert_value(&[2], None);
+		assert!(changeset.get(&[2]).is_some());
+		changeset.assert_value(&[3], Some(&[30]));
+	}
+
+	#[test]
+	fn replay_from_log_works() {
+		let log = vec![
+			(vec![1], Some(vec![1]), 0),
+			(vec![2], Some(vec![2]), 1),
+			(vec![1], None, 2),
+			(vec![3], Some(vec![3]), 2),
+		];
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.replay_from_log(&log, 0);
+		assert_eq!(overlay.storage(&[1]), Some(None));
+		assert_eq!(overlay.storage(&[2]), Some(Some(&[2][..])));
+		assert_eq!(overlay.storage(&[3]), Some(Some(&[3][..])));
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.replay_from_log(&log, 2);
+		assert_eq!(overlay.storage(&[1]), Some(None));
+		assert_eq!(overlay.storage(&[2]), None);
+		assert_eq!(overlay.storage(&[3]), Some(Some(&[3][..])));
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.replay_from_log(&log, 3);
+		assert!(overlay.is_empty());
+	}
+
+	#[test]
+	fn keys_added_in_last_commit_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.commit_prospective();
+		assert_eq!(overlay.keys_added_in_last_commit(), None);
+
+		overlay.enable_last_commit_tracking();
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.set_storage(vec![2], None);
+		overlay.commit_prospective();
+		assert_eq!(overlay.keys_added_in_last_commit(), Some(&[vec![2], vec![3]][..]));
+
+		overlay.commit_prospective();
+		assert_eq!(overlay.keys_added_in_last_commit(), Some(&[][..]));
+	}
+
+	#[test]
+	fn count_changes_in_prefix_works() {
+		let changeset: OverlayedChangeSet = vec![
+			(vec![1], Some(vec![1]).into()),
+			(vec![1, 1], None.into()),
+			(vec![1, 2], Some(vec![2]).into()),
+			(vec![2, 1], Some(vec![3]).into()),
+		].into_iter().collect();
+
+		assert_eq!(changeset.count_changes_in_prefix(&[1]), 3);
+		assert_eq!(changeset.count_changes_in_prefix(&[1, 2]), 1);
+		assert_eq!(changeset.count_changes_in_prefix(&[]), 4);
+		assert_eq!(changeset.count_changes_in_prefix(&[3]), 0);
+	}
+
+	#[test]
+	fn rollback_all_and_reset_works() {
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_collect_extrinsics(true);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.commit_prospective();
+		overlay.set_storage(vec![2], Some(vec![2]));
+
+		overlay.rollback_all_and_reset();
+		assert!(overlay.is_empty());
+		assert_eq!(overlay.storage(&[1]), None);
+		assert_eq!(overlay.storage(&[2]), None);
+		assert!(overlay.collect_extrinsics);
+		assert_eq!(overlay.transaction_rolled_back_count(), 1);
+	}
+
+	#[test]
+	fn import_committed_works() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut other = OverlayedChanges::default();
+		other.set_storage(vec![1], Some(vec![1]));
+		other.set_storage(vec![2], None);
+		other.set_child_storage(&child_info, vec![3], Some(vec![3]));
+		other.commit_prospective();
+
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![10]));
+		overlay.commit_prospective();
+		overlay.import_committed(other.committed);
+
+		assert!(overlay.has_uncommitted_changes());
+		assert_eq!(overlay.storage(&[1]), Some(Some(&[1][..])));
+		assert_eq!(overlay.storage(&[2]), Some(None));
+		assert_eq!(overlay.child_storage(&child_info, &[3]), Some(Some(&[3][..])));
+		overlay.committed.assert_value(&[1], Some(&[10]));
+	}
+
+	#[test]
+	fn coalesce_extrinsic_indices_works() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(vec![1], Some(vec![1]), Some(0));
+		changeset.set(vec![2], Some(vec![2]), Some(1));
+		changeset.set(vec![1], None, Some(1));
+		changeset.set(vec![3], Some(vec![3]), None);
+
+		let coalesced = changeset.coalesce_extrinsic_indices();
+		assert_eq!(coalesced.len(), 2);
+		assert_eq!(coalesced[&0], vec![&vec![1]]);
+		assert_eq!(coalesced[&1], vec![&vec![1], &vec![2]]);
+		assert!(OverlayedChangeSet::default().coalesce_extrinsic_indices().is_empty());
+	}
+
+	#[test]
+	fn gc_tombstones_works() {
+		let mut changeset: OverlayedChangeSet = vec![
+			(vec![1], Some(vec![1]).into()),
+			(vec![2], None.into()),
+			(vec![3], None.into()),
+		].into_iter().collect();
+
+		changeset.gc_tombstones(|key| key == &[2]);
+		assert_eq!(changeset.top.keys().collect::<Vec<_>>(), vec![&vec![1], &vec![2]]);
+		changeset.assert_value(&[1], Some(&[1]));
+	}
+
+	#[test]
+	fn group_by_module_prefix_works() {
+		let module_a = [1u8; 16];
+		let module_b = [2u8; 16];
+		let key = |prefix: &[u8; 16], suffix: u8| {
+			let mut key = prefix.to_vec();
+			key.push(suffix);
+			key
+		};
+		let changeset: OverlayedChangeSet = vec![
+			(key(&module_a, 2), Some(vec![1]).into()),
+			(key(&module_a, 1), None.into()),
+			(key(&module_b, 1), Some(vec![2]).into()),
+			(module_b.to_vec(), Some(vec![3]).into()),
+			(vec![1; 15], Some(vec![4]).into()),
+		].into_iter().collect();
 
-		let (root, transaction) = backend.full_storage_root(delta, child_delta_iter);
+		let groups = changeset.group_by_module_prefix();
+		assert_eq!(groups.len(), 2);
+		assert_eq!(groups[&module_a], vec![&key(&module_a, 1), &key(&module_a, 2)]);
+		assert_eq!(groups[&module_b], vec![&module_b.to_vec(), &key(&module_b, 1)]);
+	}
 
-		cache.transaction = Some(transaction);
-		cache.transaction_storage_root = Some(root);
+	#[test]
+	fn largest_value_key_works() {
+		let changeset: OverlayedChangeSet = vec![
+			(vec![1], Some(vec![1, 2]).into()),
+			(vec![2], Some(vec![1, 2, 3]).into()),
+			(vec![3], None.into()),
+		].into_iter().collect();
+		assert_eq!(changeset.largest_value_key(), Some((&vec![2], 3)));
 
-		root
+		let deleted: OverlayedChangeSet = vec![(vec![1], None.into())].into_iter().collect();
+		assert_eq!(deleted.largest_value_key(), None);
 	}
 
-	/// Generate the changes trie root.
-	///
-	/// Returns the changes trie root and caches the storage transaction into the given `cache`.
-	///
-	/// # Panics
-	///
-	/// Panics on storage error, when `panic_on_storage_error` is set.
-	pub fn changes_trie_root<'a, H: Hasher, N: BlockNumber, B: Backend<H>>(
-		&self,
-		backend: &B,
-		changes_trie_state: Option<&'a ChangesTrieState<'a, H, N>>,
-		parent_hash: H::Out,
-		panic_on_storage_error: bool,
-		cache: &mut StorageTransactionCache<B::Transaction, H, N>,
-	) -> Result<Option<H::Out>, ()> where H::Out: Ord + Encode + 'static {
-		build_changes_trie::<_, H, N>(
-			backend,
-			changes_trie_state,
-			self,
-			parent_hash,
-			panic_on_storage_error,
-		).map(|r| {
-			let root = r.as_ref().map(|r| r.1).clone();
-			cache.changes_trie_transaction = Some(r.map(|(db, _, cache)| (db, cache)));
-			cache.changes_trie_transaction_storage_root = Some(root);
-			root
-		})
+	#[test]
+	fn prune_by_predicate_works() {
+		let mut changeset: OverlayedChangeSet = vec![
+			(vec![1], Some(vec![1]).into()),
+			(vec![2], Some(vec![20]).into()),
+			(vec![3], None.into()),
+		].into_iter().collect();
+
+		changeset.prune_by_predicate(|_, value| value.value().map_or(false, |v| v[0] >= 10));
+		assert!(changeset.get(&[2]).is_none());
+		changeset.assert_value(&[1], Some(&[1]));
+		assert!(changeset.get(&[3]).is_some());
 	}
 
-	/// Get child info for a storage key.
-	/// Take the latest value so prospective first.
-	pub fn default_child_info(&self, storage_key: &[u8]) -> Option<&ChildInfo> {
-		if let Some((_, ci)) = self.prospective.children_default.get(storage_key) {
-			return Some(&ci);
-		}
-		if let Some((_, ci)) = self.committed.children_default.get(storage_key) {
-			return Some(&ci);
-		}
-		None
+	#[test]
+	fn has_key_in_prefix_works() {
+		let changeset: OverlayedChangeSet = vec![
+			(vec![1, 1], Some(vec![1]).into()),
+			(vec![2, 1], None.into()),
+		].into_iter().collect();
+
+		assert!(changeset.has_key_in_prefix(&[1]));
+		assert!(changeset.has_key_in_prefix(&[1, 1]));
+		assert!(changeset.has_key_in_prefix(&[]));
+		assert!(!changeset.has_key_in_prefix(&[2]));
+		assert!(!changeset.has_key_in_prefix(&[3]));
 	}
 
-	/// Returns the next (in lexicographic order) storage key in the overlayed alongside its value.
-	/// If no value is next then `None` is returned.
-	pub fn next_storage_key_change(&self, key: &[u8]) -> Option<(&[u8], &OverlayedValue)> {
-		let range = (ops::Bound::Excluded(key), ops::Bound::Unbounded);
+	#[test]
+	fn is_conflict_free_with_works() {
+		let child_info = ChildInfo::new_default(b"child");
+		let child = |key: u8| -> (BTreeMap<StorageKey, OverlayedValue>, ChildInfo) {
+			(vec![(vec![key], Some(vec![key]).into())].into_iter().collect(), child_info.clone())
+		};
 
-		let next_prospective_key = self.prospective.top
-			.range::<[u8], _>(range)
-			.next()
-			.map(|(k, v)| (&k[..], v));
+		let mut a: OverlayedChangeSet = vec![(vec![1], Some(vec![1]).into())].into_iter().collect();
+		let mut b: OverlayedChangeSet = vec![(vec![2], None.into())].into_iter().collect();
+		assert!(a.is_conflict_free_with(&b));
+		assert!(b.is_conflict_free_with(&a));
 
-		let next_committed_key = self.committed.top
-			.range::<[u8], _>(range)
-			.next()
-			.map(|(k, v)| (&k[..], v));
+		a.children_default.insert(child_info.storage_key().to_vec(), child(1));
+		b.children_default.insert(child_info.storage_key().to_vec(), child(2));
+		assert!(a.is_conflict_free_with(&b));
 
-		match (next_committed_key, next_prospective_key) {
-			// Committed is strictly less than prospective
-			(Some(committed_key), Some(prospective_key)) if committed_key.0 < prospective_key.0 =>
-				Some(committed_key),
-			(committed_key, None) => committed_key,
-			// Prospective key is less or equal to committed or committed doesn't exist
-			(_, prospective_key) => prospective_key,
-		}
+		b.children_default.insert(child_info.storage_key().to_vec(), child(1));
+		assert!(!a.is_conflict_free_with(&b));
+
+		let deleted: OverlayedChangeSet = vec![(vec![1], None.into())].into_iter().collect();
+		assert!(!a.is_conflict_free_with(&deleted));
 	}
 
-	/// Returns the next (in lexicographic order) child storage key in the overlayed alongside its
-	/// value.  If no value is next then `None` is returned.
-	pub fn next_child_storage_key_change(
-		&self,
-		storage_key: &[u8],
-		key: &[u8]
-	) -> Option<(&[u8], &OverlayedValue)> {
-		let range = (ops::Bound::Excluded(key), ops::Bound::Unbounded);
+	#[test]
+	fn simulate_commit_works() {
+		let mut overlay = OverlayedChanges::default();
+		assert!(overlay.simulate_commit().is_none());
 
-		let next_prospective_key = self.prospective.children_default.get(storage_key)
-			.and_then(|(map, _)| map.range::<[u8], _>(range).next().map(|(k, v)| (&k[..], v)));
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.commit_prospective();
+		overlay.set_storage(vec![1], None);
+		overlay.set_storage(vec![2], Some(vec![2]));
 
-		let next_committed_key = self.committed.children_default.get(storage_key)
-			.and_then(|(map, _)| map.range::<[u8], _>(range).next().map(|(k, v)| (&k[..], v)));
+		let simulated = overlay.simulate_commit().expect("There are prospective changes");
+		simulated.assert_value(&[1], None);
+		assert!(simulated.get(&[1]).is_some());
+		simulated.assert_value(&[2], Some(&[2]));
 
-		match (next_committed_key, next_prospective_key) {
-			// Committed is strictly less than prospective
-			(Some(committed_key), Some(prospective_key)) if committed_key.0 < prospective_key.0 =>
-				Some(committed_key),
-			(committed_key, None) => committed_key,
-			// Prospective key is less or equal to committed or committed doesn't exist
-			(_, prospective_key) => prospective_key,
-		}
+		assert!(overlay.has_uncommitted_changes());
+		overlay.committed.assert_value(&[1], Some(&[1]));
+		assert_eq!(overlay.transaction_committed_count(), 1);
+
+		overlay.commit_prospective();
+		assert_eq!(overlay.committed, simulated);
 	}
-}
 
-#[cfg(test)]
-impl From<Option<StorageValue>> for OverlayedValue {
-	fn from(value: Option<StorageValue>) -> OverlayedValue {
-		OverlayedValue { value, ..Default::default() }
+	#[test]
+	fn simulate_rollback_works() {
+		let mut overlay = OverlayedChanges::default();
+		assert!(overlay.simulate_rollback().is_none());
+
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.commit_prospective();
+		overlay.set_storage(vec![1], None);
+
+		let simulated = overlay.simulate_rollback().expect("There are prospective changes");
+		simulated.assert_value(&[1], Some(&[1]));
+		assert_eq!(overlay.storage(&[1]), Some(None));
+
+		overlay.discard_prospective();
+		assert_eq!(overlay.committed, simulated);
 	}
-}
 
-#[cfg(test)]
-mod tests {
-	use hex_literal::hex;
-	use sp_core::{
-		Blake2Hasher, traits::Externalities, storage::well_known_keys::EXTRINSIC_INDEX,
-	};
-	use crate::InMemoryBackend;
-	use crate::ext::Ext;
-	use super::*;
+	#[test]
+	fn key_prefix_stats_works() {
+		let changeset: OverlayedChangeSet = vec![
+			(vec![1], Some(vec![1]).into()),
+			(vec![1, 1], None.into()),
+			(vec![1, 1, 1], Some(vec![1]).into()),
+			(vec![1, 2, 1], Some(vec![1]).into()),
+		].into_iter().collect();
 
-	fn strip_extrinsic_index(map: &BTreeMap<StorageKey, OverlayedValue>)
-		-> BTreeMap<StorageKey, OverlayedValue>
-	{
-		let mut clone = map.clone();
-		clone.remove(&EXTRINSIC_INDEX.to_vec());
-		clone
+		assert_eq!(
+			changeset.key_prefix_stats(2).into_iter().collect::<Vec<_>>(),
+			vec![(vec![1], 1), (vec![1, 1], 2), (vec![1, 2], 1)],
+		);
+		assert_eq!(
+			changeset.key_prefix_stats(0).into_iter().collect::<Vec<_>>(),
+			vec![(vec![], 4)],
+		);
 	}
 
 	#[test]
-	fn overlayed_storage_works() {
-		let mut overlayed = OverlayedChanges::default();
+	fn apply_predicate_to_range_works() {
+		let mut changeset: OverlayedChangeSet = (0u8..5)
+			.map(|i| (vec![i], Some(vec![i]).into()))
+			.collect();
 
-		let key = vec![42, 69, 169, 142];
+		let mut visited = Vec::new();
+		changeset.apply_predicate_to_range(&[1], &[3], |key, value| {
+			visited.push(key.clone());
+			if key == &[1] {
+				value.as_mut().expect("All values are set").push(10);
+			} else {
+				*value = None;
+			}
+		});
 
-		assert!(overlayed.storage(&key).is_none());
+		assert_eq!(visited, vec![vec![1], vec![2]]);
+		changeset.assert_value(&[0], Some(&[0]));
+		changeset.assert_value(&[1], Some(&[1, 10]));
+		changeset.assert_value(&[2], None);
+		assert!(changeset.get(&[2]).is_some());
+		changeset.assert_value(&[3], Some(&[3]));
+	}
 
-		overlayed.set_storage(key.clone(), Some(vec![1, 2, 3]));
-		assert_eq!(overlayed.storage(&key).unwrap(), Some(&[1, 2, 3][..]));
+	#[test]
+	fn pending_commit_size_bytes_works() {
+		let changeset: OverlayedChangeSet = vec![
+			(vec![1, 2], Some(vec![1, 2, 3]).into()),
+			(vec![3], None.into()),
+		].into_iter().collect();
 
-		overlayed.commit_prospective();
-		assert_eq!(overlayed.storage(&key).unwrap(), Some(&[1, 2, 3][..]));
+		// (2 + 1 + 3) bytes for the first change, (1 + 1 + 0) for the deletion.
+		assert_eq!(changeset.pending_commit_size_bytes(), 8);
+		assert_eq!(OverlayedChangeSet::default().pending_commit_size_bytes(), 0);
+	}
 
-		overlayed.set_storage(key.clone(), Some(vec![]));
-		assert_eq!(overlayed.storage(&key).unwrap(), Some(&[][..]));
+	#[test]
+	fn transaction_commit_preview_works() {
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(overlay.transaction_commit_preview(), None);
 
-		overlayed.set_storage(key.clone(), None);
-		assert!(overlayed.storage(&key).unwrap().is_none());
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], Some(vec![2]));
+		overlay.commit_prospective();
 
-		overlayed.discard_prospective();
-		assert_eq!(overlayed.storage(&key).unwrap(), Some(&[1, 2, 3][..]));
+		overlay.set_storage(vec![3], Some(vec![3]));
+		overlay.set_storage(vec![2], None);
+		overlay.set_storage(vec![1], Some(vec![1]));
+		assert_eq!(overlay.transaction_commit_preview(), Some(vec![
+			(vec![2], None),
+			(vec![3], Some(vec![3])),
+		]));
 
-		overlayed.set_storage(key.clone(), None);
-		overlayed.commit_prospective();
-		assert!(overlayed.storage(&key).unwrap().is_none());
+		assert!(overlay.has_uncommitted_changes());
+		overlay.committed.assert_value(&[2], Some(&[2]));
 	}
 
 	#[test]
-	fn overlayed_storage_root_works() {
-		let initial: BTreeMap<_, _> = vec![
-			(b"doe".to_vec(), b"reindeer".to_vec()),
-			(b"dog".to_vec(), b"puppyXXX".to_vec()),
-			(b"dogglesworth".to_vec(), b"catXXX".to_vec()),
-			(b"doug".to_vec(), b"notadog".to_vec()),
+	fn serialize_for_proof_works() {
+		let changeset: OverlayedChangeSet = vec![
+			(vec![2], Some(vec![2]).into()),
+			(vec![1], None.into()),
 		].into_iter().collect();
-		let backend = InMemoryBackend::<Blake2Hasher>::from(initial);
-		let mut overlay = OverlayedChanges {
-			committed: vec![
-				(b"dog".to_vec(), Some(b"puppy".to_vec()).into()),
-				(b"dogglesworth".to_vec(), Some(b"catYYY".to_vec()).into()),
-				(b"doug".to_vec(), Some(vec![]).into()),
-			].into_iter().collect(),
-			prospective: vec![
-				(b"dogglesworth".to_vec(), Some(b"cat".to_vec()).into()),
-				(b"doug".to_vec(), None.into()),
-			].into_iter().collect(),
-			..Default::default()
-		};
-
-		let mut offchain_overlay = Default::default();
-		let mut cache = StorageTransactionCache::default();
-		let mut ext = Ext::new(
-			&mut overlay,
-			&mut offchain_overlay,
-			&mut cache,
-			&backend,
-			crate::changes_trie::disabled_state::<_, u64>(),
-			None,
-		);
-		const ROOT: [u8; 32] = hex!("39245109cef3758c2eed2ccba8d9b370a917850af3824bc8348d505df2c298fa");
 
-		assert_eq!(&ext.storage_root()[..], &ROOT);
+		let hash = |key: &[u8]| sp_core::hashing::blake2_256(key).to_vec();
+		assert_eq!(changeset.serialize_for_proof(hash), vec![
+			(hash(&[1]), None::<StorageValue>.encode()),
+			(hash(&[2]), Some(vec![2u8]).encode()),
+		]);
+		assert!(OverlayedChangeSet::default().serialize_for_proof(hash).is_empty());
 	}
 
 	#[test]
-	fn extrinsic_changes_are_collected() {
+	fn transaction_pre_image_hash_works() {
 		let mut overlay = OverlayedChanges::default();
-		overlay.set_collect_extrinsics(true);
+		assert_eq!(overlay.transaction_pre_image_hash(), None);
 
-		overlay.set_storage(vec![100], Some(vec![101]));
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.commit_prospective();
+		overlay.set_storage(vec![2], Some(vec![2]));
+		let hash = overlay.transaction_pre_image_hash().expect("There are prospective changes");
 
-		overlay.set_extrinsic_index(0);
-		overlay.set_storage(vec![1], Some(vec![2]));
+		// Only the keys of the prospective changes are hashed.
+		overlay.set_storage(vec![2], Some(vec![3]));
+		assert_eq!(overlay.transaction_pre_image_hash(), Some(hash));
 
-		overlay.set_extrinsic_index(1);
-		overlay.set_storage(vec![3], Some(vec![4]));
+		overlay.set_storage(vec![3], None);
+		assert_ne!(overlay.transaction_pre_image_hash(), Some(hash));
 
-		overlay.set_extrinsic_index(2);
-		overlay.set_storage(vec![1], Some(vec![6]));
+		let mut other = OverlayedChanges::default();
+		other.set_storage(vec![1], Some(vec![10]));
+		other.commit_prospective();
+		other.set_storage(vec![2], Some(vec![2]));
+		assert_ne!(other.transaction_pre_image_hash(), Some(hash));
+	}
 
-		assert_eq!(strip_extrinsic_index(&overlay.prospective.top),
-			vec![
-				(vec![1], OverlayedValue { value: Some(vec![6]),
-				 extrinsics: Some(vec![0, 2].into_iter().collect()) }),
-				(vec![3], OverlayedValue { value: Some(vec![4]),
-				 extrinsics: Some(vec![1].into_iter().collect()) }),
-				(vec![100], OverlayedValue { value: Some(vec![101]),
-				 extrinsics: Some(vec![NO_EXTRINSIC_INDEX].into_iter().collect()) }),
-			].into_iter().collect());
+	#[test]
+	fn strip_extrinsic_data_works() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(vec![1], Some(vec![1]), Some(0));
+		changeset.children_default.insert(child_info.storage_key().to_vec(), (
+			vec![(vec![1], OverlayedValue {
+				value: Some(vec![1]),
+				extrinsics: Some(vec![1].into_iter().collect()),
+			})].into_iter().collect(),
+			child_info.clone(),
+		));
+		assert_eq!(changeset.total_extrinsic_count(), 2);
 
-		overlay.commit_prospective();
+		changeset.strip_extrinsic_data();
+		assert_eq!(changeset.total_extrinsic_count(), 0);
+		assert!(changeset.extrinsic_write_counts().is_empty());
+		changeset.assert_value(&[1], Some(&[1]));
+	}
 
-		overlay.set_extrinsic_index(3);
-		overlay.set_storage(vec![3], Some(vec![7]));
+	#[test]
+	fn key_version_history_works() {
+		let mut overlay = OverlayedChanges::default();
+		assert_eq!(overlay.key_version_history(&[1]), None);
 
-		overlay.set_extrinsic_index(4);
-		overlay.set_storage(vec![1], Some(vec![8]));
+		overlay.set_storage(vec![1], Some(vec![1]));
+		assert_eq!(overlay.key_version_history(&[1]), Some(vec![(Some(&vec![1]), 1)]));
 
-		assert_eq!(strip_extrinsic_index(&overlay.committed.top),
-			vec![
-				(vec![1], OverlayedValue { value: Some(vec![6]),
-				 extrinsics: Some(vec![0, 2].into_iter().collect()) }),
-				(vec![3], OverlayedValue { value: Some(vec![4]),
-				 extrinsics: Some(vec![1].into_iter().collect()) }),
-				(vec![100], OverlayedValue { value: Some(vec![101]),
-				 extrinsics: Some(vec![NO_EXTRINSIC_INDEX].into_iter().collect()) }),
-			].into_iter().collect());
+		overlay.commit_prospective();
+		assert_eq!(overlay.key_version_history(&[1]), Some(vec![(Some(&vec![1]), 0)]));
 
-		assert_eq!(strip_extrinsic_index(&overlay.prospective.top),
-			vec![
-				(vec![1], OverlayedValue { value: Some(vec![8]),
-				 extrinsics: Some(vec![4].into_iter().collect()) }),
-				(vec![3], OverlayedValue { value: Some(vec![7]),
-				 extrinsics: Some(vec![3].into_iter().collect()) }),
-			].into_iter().collect());
+		overlay.set_storage(vec![1], None);
+		assert_eq!(
+			overlay.key_version_history(&[1]),
+			Some(vec![(Some(&vec![1]), 0), (None, 1)]),
+		);
+	}
 
-		overlay.commit_prospective();
+	#[test]
+	fn export_as_genesis_storage_works() {
+		let child_info = ChildInfo::new_default(b"child");
+		let mut overlay = OverlayedChanges::default();
+		overlay.set_storage(vec![1], Some(vec![1]));
+		overlay.set_storage(vec![2], None);
+		overlay.set_child_storage(&child_info, vec![3], Some(vec![3]));
+		overlay.set_child_storage(&child_info, vec![4], None);
+		overlay.set_child_storage(&ChildInfo::new_default(b"empty"), vec![5], None);
 
-		assert_eq!(strip_extrinsic_index(&overlay.committed.top),
-			vec![
-				(vec![1], OverlayedValue { value: Some(vec![8]),
-				 extrinsics: Some(vec![0, 2, 4].into_iter().collect()) }),
-				(vec![3], OverlayedValue { value: Some(vec![7]),
-				 extrinsics: Some(vec![1, 3].into_iter().collect()) }),
-				(vec![100], OverlayedValue { value: Some(vec![101]),
-				 extrinsics: Some(vec![NO_EXTRINSIC_INDEX].into_iter().collect()) }),
-			].into_iter().collect());
+		let storage = overlay.prospective.export_as_genesis_storage();
+		assert_eq!(storage.top, vec![(vec![1], vec![1])].into_iter().collect::<BTreeMap<_, _>>());
+		assert_eq!(storage.children_default.len(), 1);
+		let child = &storage.children_default[child_info.storage_key()];
+		assert_eq!(child.data, vec![(vec![3], vec![3])].into_iter().collect::<BTreeMap<_, _>>());
+		assert_eq!(child.child_info, child_info);
 
-		assert_eq!(overlay.prospective,
-			Default::default());
+		let mut imported = OverlayedChangeSet::default();
+		imported.apply_genesis(storage);
+		imported.assert_value(&[1], Some(&[1]));
+		assert!(imported.get(&[2]).is_none());
+	}
+
+	#[test]
+	fn iter_by_extrinsic_works() {
+		let mut changeset = OverlayedChangeSet::default();
+		changeset.set(vec![2], Some(vec![2]), Some(0));
+		changeset.set(vec![1], Some(vec![1]), Some(1));
+		changeset.set(vec![2], None, Some(1));
+		changeset.set(vec![3], Some(vec![3]), None);
+
+		let keys = |index| changeset.iter_by_extrinsic(index)
+			.map(|(key, _)| key.clone())
+			.collect::<Vec<_>>();
+		assert_eq!(keys(0), vec![vec![2]]);
+		assert_eq!(keys(1), vec![vec![1], vec![2]]);
+		assert!(keys(2).is_empty());
 	}
 
 	#[test]
@@ -84,6 +84,14 @@ impl<H: Hasher, N: ChangesTrieBlockNumber> TestExternalities<H, N>
 		Self::new_with_code(&[], storage)
 	}
 
+	/// Create a new instance of `TestExternalities` with storage and an empty in-memory
+	/// keystore registered.
+	pub fn with_keystore_and_storage(storage: Storage) -> Self {
+		let mut ext = Self::new(storage);
+		ext.register_extension(sp_core::traits::KeystoreExt(sp_core::testing::KeyStore::new()));
+		ext
+	}
+
 	/// New empty test externalities.
 	pub fn new_empty() -> Self {
 		Self::new_with_code(&[], Storage::default())
@@ -226,6 +234,7 @@ impl<H, N> sp_externalities::ExtensionStore for TestExternalities<H, N> where
 mod tests {
 	use super::*;
 	use sp_core::traits::Externalities;
+	use sp_externalities::ExtensionStore;
 	use sp_runtime::traits::BlakeTwo256;
 	use hex_literal::hex;
 
@@ -251,6 +260,24 @@ mod tests {
 		assert_eq!(&ext.storage(CODE).unwrap(), &code);
 	}
 
+	#[test]
+	fn with_keystore_and_storage_works() {
+		let storage = Storage {
+			top: vec![(b"doe".to_vec(), b"reindeer".to_vec())].into_iter().collect(),
+			children_default: Default::default(),
+		};
+		let mut ext = TestExternalities::<BlakeTwo256, u64>::with_keystore_and_storage(storage);
+		let keystore = TypeId::of::<sp_core::traits::KeystoreExt>();
+		assert!(ext.extension_by_type_id(keystore).is_some());
+
+		let mut ext = ext.ext();
+		assert_eq!(ext.storage(b"doe"), Some(b"reindeer".to_vec()));
+		assert!(ext.extension_by_type_id(keystore).is_some());
+
+		let mut plain = TestExternalities::<BlakeTwo256, u64>::default();
+		assert!(plain.extension_by_type_id(keystore).is_none());
+	}
+
 	#[test]
 	fn check_send() {
 		fn assert_send<T: Send>() {}